@@ -1,7 +1,8 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 1024K
+  /* the last four 128K sectors are reserved: 8 and 9 for energy meters, 10 and 11 for settings */
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 512K
   CCMRAM (rwx) : ORIGIN = 0x10000000, LENGTH = 64K
   RAM (rwx) : ORIGIN = 0x20000000, LENGTH = 128K
 }
@@ -0,0 +1,158 @@
+use heapless::Deque;
+
+use crate::modbus::MAX_ADU_LEN;
+
+// ram set aside for captured frames, the oldest are discarded when it runs full
+const CAPTURE_LEN: usize = 16384;
+
+// time (8), direction (1) and length (2) stored ahead of each frame
+const RECORD_HEADER_LEN: usize = 11;
+
+// link type DLT_USER0, each packet is a direction byte followed by the RTU frame
+const LINKTYPE_USER0: u32 = 147;
+
+const PCAP_HEADER_LEN: usize = 24;
+const PACKET_HEADER_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+impl Direction {
+    pub fn to(&self) -> u8 {
+        match self {
+            Self::Rx => { 0x00 },
+            Self::Tx => { 0x01 },
+        }
+    }
+}
+
+/// Ring buffer of the frames seen and sent on the bus, exported in libpcap format
+pub struct Capture {
+    enabled: bool,
+    data: Deque<u8, CAPTURE_LEN>,
+    records: u32,
+    discarded: u32,
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            data: Deque::new(),
+            records: 0,
+            discarded: 0,
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.records = 0;
+        self.discarded = 0;
+    }
+
+    /// Frames held in the buffer
+    pub fn records(&self) -> u32 {
+        self.records
+    }
+
+    /// Frames pushed out of the buffer by newer ones
+    pub fn discarded(&self) -> u32 {
+        self.discarded
+    }
+
+    /// Store a frame seen at `time_us` of uptime
+    pub fn record(&mut self, time_us: u64, direction: Direction, adu: &[u8]) {
+        if !self.enabled || adu.is_empty() {
+            return;
+        }
+
+        let adu = &adu[..adu.len().min(MAX_ADU_LEN)];
+        let needed = RECORD_HEADER_LEN + adu.len();
+        while CAPTURE_LEN - self.data.len() < needed {
+            self.discard_oldest();
+        }
+
+        for byte in time_us.to_le_bytes() {
+            let _ = self.data.push_back(byte);
+        }
+        let _ = self.data.push_back(direction.to());
+        for byte in (adu.len() as u16).to_le_bytes() {
+            let _ = self.data.push_back(byte);
+        }
+        for byte in adu {
+            let _ = self.data.push_back(*byte);
+        }
+        self.records += 1;
+    }
+
+    /// Size in bytes of the pcap file `write_pcap` produces
+    pub fn pcap_len(&self) -> usize {
+        let frames = self.data.len() - self.records as usize * RECORD_HEADER_LEN;
+        PCAP_HEADER_LEN + self.records as usize * (PACKET_HEADER_LEN + 1) + frames
+    }
+
+    /// Produce the capture as a pcap file, passing it to `out` piece by piece
+    ///
+    /// * `offset_us` - added to the uptime of each frame, the unix epoch of start up
+    ///   once the clock has been set.
+    pub fn write_pcap<F: FnMut(&[u8])>(&self, offset_us: u64, mut out: F) {
+        let mut header = [0u8; PCAP_HEADER_LEN];
+        header[0..4].copy_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header[4..6].copy_from_slice(&2u16.to_le_bytes());
+        header[6..8].copy_from_slice(&4u16.to_le_bytes());
+        // thiszone and sigfigs stay zero
+        header[16..20].copy_from_slice(&(MAX_ADU_LEN as u32 + 1).to_le_bytes());
+        header[20..24].copy_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        out(&header);
+
+        let mut bytes = self.data.iter().copied();
+        let mut frame = [0u8; MAX_ADU_LEN + 1];
+        while let Some(first) = bytes.next() {
+            let mut record = [0u8; RECORD_HEADER_LEN];
+            record[0] = first;
+            for byte in record[1..].iter_mut() {
+                *byte = bytes.next().unwrap_or(0);
+            }
+
+            let time_us = u64::from_le_bytes(record[0..8].try_into().unwrap()) + offset_us;
+            let len = u16::from_le_bytes([record[9], record[10]]) as usize;
+            frame[0] = record[8];
+            for byte in frame[1..len + 1].iter_mut() {
+                *byte = bytes.next().unwrap_or(0);
+            }
+
+            let mut packet = [0u8; PACKET_HEADER_LEN];
+            packet[0..4].copy_from_slice(&((time_us / 1_000_000) as u32).to_le_bytes());
+            packet[4..8].copy_from_slice(&((time_us % 1_000_000) as u32).to_le_bytes());
+            packet[8..12].copy_from_slice(&(len as u32 + 1).to_le_bytes());
+            packet[12..16].copy_from_slice(&(len as u32 + 1).to_le_bytes());
+            out(&packet);
+            out(&frame[..len + 1]);
+        }
+    }
+
+    fn discard_oldest(&mut self) {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        for byte in header.iter_mut() {
+            *byte = self.data.pop_front().unwrap_or(0);
+        }
+
+        let len = u16::from_le_bytes([header[9], header[10]]);
+        for _ in 0..len {
+            self.data.pop_front();
+        }
+        self.records -= 1;
+        self.discarded += 1;
+    }
+}
@@ -0,0 +1,104 @@
+use core::cell::Cell;
+use core::fmt;
+
+use crate::hal::pac::TIM2;
+use crate::hal::timer::Counter;
+use fugit::Instant;
+
+/// System time keeping on top of sys_timer
+///
+/// extends the 32 bit msec counter to 64 bits of uptime and, once the master
+/// has set the time, maps uptime onto unix epoch time
+pub struct Clock<'a> {
+    sys_timer: &'a Counter<TIM2, 1000>,
+    last_ticks: Cell<u32>,
+    wraps: Cell<u32>,
+    epoch_offset: Cell<Option<u64>>,
+}
+
+impl <'a>Clock<'a> {
+    pub fn new(sys_timer: &'a Counter<TIM2, 1000>) -> Self {
+        Self {
+            sys_timer,
+            last_ticks: Cell::new(sys_timer.now().ticks()),
+            wraps: Cell::new(0),
+            epoch_offset: Cell::new(None),
+        }
+    }
+
+    pub fn now(&self) -> Instant<u32, 1, 1000> {
+        self.sys_timer.now()
+    }
+
+    /// Msec since boot, must be called at least once per sys_timer wrap (~49 days)
+    pub fn uptime_ms(&self) -> u64 {
+        let ticks = self.sys_timer.now().ticks();
+        if ticks < self.last_ticks.get() {
+            self.wraps.set(self.wraps.get() + 1);
+        }
+        self.last_ticks.set(ticks);
+
+        ((self.wraps.get() as u64) << 32) | ticks as u64
+    }
+
+    /// Set the wall clock time
+    ///
+    /// * `epoch` - seconds since 1970-01-01T00:00:00Z.
+    pub fn set_epoch(&self, epoch: u32) {
+        let offset = (epoch as u64 * 1000).saturating_sub(self.uptime_ms());
+        self.epoch_offset.set(Some(offset));
+    }
+
+    /// Seconds since the unix epoch, None until the time has been set
+    pub fn epoch(&self) -> Option<u32> {
+        self.epoch_ms(self.uptime_ms()).map(|ms| (ms / 1000) as u32)
+    }
+
+    /// Convert an uptime in msec to unix epoch msec, None until the time has been set
+    pub fn epoch_ms(&self, uptime_ms: u64) -> Option<u64> {
+        self.epoch_offset.get().map(|offset| offset + uptime_ms)
+    }
+
+    /// Displayable form of an uptime, in real time when the clock has been set
+    pub fn timestamp(&self, uptime_ms: u64) -> Timestamp {
+        match self.epoch_ms(uptime_ms) {
+            Some(ms) => { Timestamp::Epoch(ms) },
+            None => { Timestamp::Uptime(uptime_ms) },
+        }
+    }
+}
+
+pub enum Timestamp {
+    Uptime(u64),
+    Epoch(u64),
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Timestamp::Uptime(ms) => { write!(f, "{:>10}", ms) },
+            Timestamp::Epoch(ms) => {
+                let secs = ms / 1000;
+                let (year, month, day) = civil_from_days(secs / 86400);
+                let time = secs % 86400;
+                write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+                    year, month, day, time / 3600, (time / 60) % 60, time % 60, ms % 1000)
+            },
+        }
+    }
+}
+
+/// Gregorian (year, month, day) of a count of days since 1970-01-01
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
@@ -1,24 +1,80 @@
 
-use crate::hal::pac::TIM2;
-use crate::hal::timer::Counter;
 use crate::hal::prelude::*;
 use fugit::Instant;
+use heapless::Vec;
 
 use crate::{KeyEvent, LightPorts, TM1638};
 use smart_leds::RGB8;
 use crate::Colors;
 
 use crate::modbus::*;
+use crate::event_log::*;
+use crate::clock::Clock;
+use crate::settings::ResponseDelay;
 
-const CURRENT_STATE: u16 = 0x3040;
-const CHARGE_CONTROL: u16 = 0x4010;
-const SERVICE_CONTROL: u16 = 0x4012;
+const UPTIME_HI: u16 = 0x3000;
+const UPTIME_LO: u16 = 0x3001;
+pub const CURRENT_STATE: u16 = 0x3040;
+const CURRENT_L1: u16 = 0x3050;
+const CURRENT_L2: u16 = 0x3051;
+const CURRENT_L3: u16 = 0x3052;
+const ACTIVE_PHASES: u16 = 0x3053;
+const OFFERED_CURRENT: u16 = 0x3054;
+const TEMPERATURE: u16 = 0x3060;
+const RCD_TRIPS: u16 = 0x3070;
+const LOCK_STATUS: u16 = 0x3074;
+const LIFETIME_ENERGY_HI: u16 = 0x3080;
+const LIFETIME_ENERGY_LO: u16 = 0x3081;
+const SESSION_ENERGY_HI: u16 = 0x3082;
+const SESSION_ENERGY_LO: u16 = 0x3083;
+const EPOCH_TIME_HI: u16 = 0x4000;
+const EPOCH_TIME_LO: u16 = 0x4001;
+pub const CHARGE_CONTROL: u16 = 0x4010;
+pub const SERVICE_CONTROL: u16 = 0x4012;
+const PHASE_SWITCH: u16 = 0x4014;
+const MAX_CURRENT: u16 = 0x4020;
+const AMBIENT_TEMPERATURE: u16 = 0x4030;
 const SECOND: u32 = 1000;
 
+// currents are in units of 0.1 A
+const DEFAULT_MAX_CURRENT: u16 = 160;
+const MAX_CURRENT_LIMIT: u16 = 320;
+
+// contactors must stay open this long while switching between 1 and 3 phases
+const PHASE_SWITCH_PAUSE: u32 = 5 * SECOND;
+
+// temperatures are in degC, registers hold them as signed units of 0.1 degC
+const DEFAULT_AMBIENT: i16 = 250;
+const THERMAL_PERIOD: u32 = 100;
+const HEAT_RATE: f32 = 0.0042;      // degC per second per amp drawn
+const COOL_RATE: f32 = 0.005;       // fraction of the rise above ambient lost per second
+const DERATE_START: f32 = 60.0;
+const DERATE_END: f32 = 80.0;
+// derating stops at the 6 A minimum a vehicle charges with (0.1 A units), so a unit at the
+// highest ambient still heats past the trip on a single phase
+const DERATE_FLOOR: u16 = 60;
+const TRIP_TEMP: f32 = 82.0;
+const TRIP_HYSTERESIS: f32 = 15.0;
+
+// a latched residual current trip is cleared by writing ARM then CONFIRM to SERVICE_CONTROL
+// or by holding the service key
+pub const RCD_RESET_ARM: u16 = 0xa5a5;
+pub const RCD_RESET_CONFIRM: u16 = 0x5a5a;
+const RCD_RESET_PRESS: u32 = 3 * SECOND;
+
+// energy is metered against a fixed phase voltage
+const NOMINAL_VOLTAGE: f32 = 230.0;
+
+// time for the connector lock actuator to travel
+const LOCK_TIME: u32 = 500;
+
 struct Registers{
     current_state: u16,
     charge_control: u16,
     service_control: u16,
+    phase_switch: u16,
+    max_current: u16,
+    ambient: u16,
 }
 
 pub struct EVCharger<'a> {
@@ -30,12 +86,36 @@ pub struct EVCharger<'a> {
     key_colors: [RGB8; 2],
     registers: Registers,
     charge_sec: u8,
-    sys_timer: &'a Counter<TIM2, 1000>,
+    clock: &'a Clock<'a>,
     charge_next: Instant<u32, 1, 1000>,
+    events: EventLog,
+    charger_phases: u8,
+    vehicle_phases: u8,
+    switch_until: Option<Instant<u32, 1, 1000>>,
+    temperature: f32,
+    thermal_next: Instant<u32, 1, 1000>,
+    start_policy: StartPolicy,
+    response_delay: ResponseDelay,
+    vehicle_paused: bool,
+    rcd_latched: bool,
+    rcd_reset_armed: bool,
+    rcd_trips: u16,
+    service_down_at: Option<Instant<u32, 1, 1000>>,
+    lock: LockState,
+    lock_until: Instant<u32, 1, 1000>,
+    lock_fault: bool,
+    start_pending: bool,
+    lifetime_wh: u32,
+    session_wh: u32,
+    energy_frac: f32,
+    epoch_hi: u16,
+    mirrored: bool,
+    /// CURRENT_STATE of the remote unit shown while mirrored
+    mirrored_state: u16,
 }
 
 impl <'a>EVCharger<'a> {
-    pub fn new(unit_id: u8, ui_bank: u8, sys_timer: &'a Counter<TIM2, 1000>,) -> Self {
+    pub fn new(unit_id: u8, ui_bank: u8, clock: &'a Clock<'a>,) -> Self {
         Self {
             unit_id,
             ui_bank,
@@ -47,26 +127,276 @@ impl <'a>EVCharger<'a> {
             registers: Registers {
                 current_state: 0x0001,
                 charge_control: 0x0000,
-                service_control: 0x0000
+                service_control: 0x0000,
+                phase_switch: 3,
+                max_current: DEFAULT_MAX_CURRENT,
+                ambient: DEFAULT_AMBIENT as u16,
             },
             charge_sec: 0,
-            sys_timer,
-            charge_next: sys_timer.now(),
+            clock,
+            charge_next: clock.now(),
+            events: EventLog::new(),
+            charger_phases: 3,
+            vehicle_phases: 3,
+            switch_until: None,
+            temperature: DEFAULT_AMBIENT as f32 / 10.0,
+            thermal_next: clock.now(),
+            start_policy: StartPolicy::MasterEnable,
+            response_delay: ResponseDelay::default(),
+            vehicle_paused: false,
+            rcd_latched: false,
+            rcd_reset_armed: false,
+            rcd_trips: 0,
+            service_down_at: None,
+            lock: LockState::Unlocked,
+            lock_until: clock.now(),
+            lock_fault: false,
+            start_pending: false,
+            lifetime_wh: 0,
+            session_wh: 0,
+            energy_frac: 0.0,
+            epoch_hi: 0,
+            mirrored: false,
+            mirrored_state: 0,
+        }
+    }
+
+    /// Advance the time based parts of the charger model
+    pub fn tick(&mut self) {
+        if self.mirrored {
+            return;
+        }
+
+        match self.switch_until {
+            Some(until) if self.clock.now() >= until => {
+                self.switch_until = None;
+                self.update = true;
+            },
+            _ => {}
+        }
+
+        if self.clock.now() >= self.thermal_next {
+            self.thermal_next = self.clock.now() + THERMAL_PERIOD.millis();
+            self.update_thermal(THERMAL_PERIOD as f32 / SECOND as f32);
+            self.update_energy(THERMAL_PERIOD as f32 / SECOND as f32);
+        }
 
+        match self.service_down_at {
+            Some(down_at) if self.rcd_latched && self.clock.now() >= down_at + RCD_RESET_PRESS.millis() => {
+                self.service_down_at = None;
+                self.reset_rcd();
+            },
+            _ => {}
+        }
+
+        self.update_lock();
+        self.update_suspension();
+    }
+
+    /// Simulate a residual current trip, the relay opens at once and the unit
+    /// stays locked out until it is reset
+    pub fn trip_rcd(&mut self) {
+        self.rcd_latched = true;
+        self.rcd_reset_armed = false;
+        self.rcd_trips = self.rcd_trips.wrapping_add(1);
+
+        let mut state = self.get_state();
+        state.charger = ChgState::Abnormal;
+        state.error = ErrState::ErrResidual;
+        state.changed = true;
+        self.set_current_state(state.to());
+        self.update = true;
+    }
+
+    fn reset_rcd(&mut self) {
+        self.rcd_latched = false;
+        self.rcd_reset_armed = false;
+        self.registers.service_control = 0x0000;
+        self.clear_fault();
+    }
+
+    pub fn current_state(&self) -> u16 {
+        self.registers.current_state
+    }
+
+    /// Hand the UI bank over to a remote unit, the local model and keys stop while mirrored
+    /// and it resumes where it was left
+    pub fn set_mirrored(&mut self, mirrored: bool) {
+        self.mirrored = mirrored;
+        self.mirrored_state = self.registers.current_state;
+        self.update = true;
+    }
+
+    pub fn is_mirrored(&self) -> bool {
+        self.mirrored
+    }
+
+    /// CURRENT_STATE last read from the mirrored remote unit
+    pub fn mirrored_state(&self) -> u16 {
+        self.mirrored_state
+    }
+
+    /// Show the CURRENT_STATE read from the mirrored remote unit
+    pub fn mirror_state(&mut self, value: u16) {
+        if self.mirrored && value != self.mirrored_state {
+            self.mirrored_state = value;
+            self.update = true;
+        }
+    }
+
+    /// State shown on the UI bank, the remote unit's while mirrored
+    fn ui_state(&self) -> UnitState {
+        if self.mirrored { UnitState::from(self.mirrored_state) } else { self.get_state() }
+    }
+
+    /// Total current drawn over all phases in units of 0.1 A
+    pub fn total_current(&self) -> u16 {
+        (0..3).map(|phase| self.phase_current(phase)).sum()
+    }
+
+    /// Power delivered in W
+    pub fn power(&self) -> u32 {
+        (NOMINAL_VOLTAGE * self.total_current() as f32 / 10.0) as u32
+    }
+
+    /// Energy delivered in the current (or last) session in Wh
+    pub fn session_energy(&self) -> u32 {
+        self.session_wh
+    }
+
+    /// Lifetime energy delivered in Wh
+    pub fn lifetime_energy(&self) -> u32 {
+        self.lifetime_wh
+    }
+
+    /// Preset the lifetime energy meter, used when restoring from flash or replacing a meter
+    pub fn set_lifetime_energy(&mut self, wh: u32) {
+        self.lifetime_wh = wh;
+        self.energy_frac = 0.0;
+    }
+
+    /// Inject (or clear) a connector lock failure, a failed lock is released when cleared
+    pub fn set_lock_fault(&mut self, fault: bool) {
+        self.lock_fault = fault;
+        if !fault && self.lock == LockState::Failed {
+            self.lock = LockState::Unlocked;
+        }
+        self.update = true;
+    }
+
+    pub fn lock_fault(&self) -> bool {
+        self.lock_fault
+    }
+
+    pub fn lock_state(&self) -> LockState {
+        self.lock
+    }
+
+    pub fn rcd_latched(&self) -> bool {
+        self.rcd_latched
+    }
+
+    pub fn rcd_trips(&self) -> u16 {
+        self.rcd_trips
+    }
+
+    /// Leave an Abnormal state, returning to where a plugged or unplugged vehicle would be
+    /// under the start policy
+    fn clear_fault(&mut self) {
+        let mut state = self.get_state();
+        state.charger = if state.connected { ChgState::Connect } else { self.idle_state() };
+        state.error = ErrState::Norminal;
+        state.changed = true;
+        self.set_current_state(state.to());
+        self.update = true;
+
+        if state.connected {
+            self.apply_start_policy();
+        }
+    }
+
+    /// Pause or resume an ongoing session depending on who is holding it up
+    fn update_suspension(&mut self) {
+        let state = self.get_state().charger;
+        if !state.in_session() {
+            return;
+        }
+
+        let next = if self.offered_current() == 0 {
+            ChgState::SuspendedEVSE
+        } else if self.vehicle_paused {
+            ChgState::SuspendedEV
+        } else {
+            ChgState::Charge
+        };
+
+        if next != state {
+            self.enter_state(next, true);
+        }
+    }
+
+    /// Meter the energy delivered over the last `dt` seconds
+    fn update_energy(&mut self, dt: f32) {
+        self.energy_frac += self.power() as f32 * dt / 3600.0;
+        if self.energy_frac >= 1.0 {
+            let wh = self.energy_frac as u32;
+            self.lifetime_wh = self.lifetime_wh.wrapping_add(wh);
+            self.session_wh = self.session_wh.wrapping_add(wh);
+            self.energy_frac -= wh as f32;
+        }
+    }
+
+    /// Step the thermal model by `dt` seconds and trip or recover the over temperature fault
+    fn update_thermal(&mut self, dt: f32) {
+        let current: f32 = (0..3).map(|phase| self.phase_current(phase) as f32 / 10.0).sum();
+        let ambient = self.registers.ambient as i16 as f32 / 10.0;
+
+        self.temperature += (HEAT_RATE * current - COOL_RATE * (self.temperature - ambient)) * dt;
+
+        // a latched residual current trip takes precedence over the temperature fault
+        if self.rcd_latched {
+            return;
+        }
+
+        let mut state = self.get_state();
+        if state.error != ErrState::ErrTemperature && self.temperature >= TRIP_TEMP {
+            state.charger = ChgState::Abnormal;
+            state.error = ErrState::ErrTemperature;
+            state.changed = true;
+            self.set_current_state(state.to());
+            self.update = true;
+        } else if state.error == ErrState::ErrTemperature && self.temperature <= TRIP_TEMP - TRIP_HYSTERESIS {
+            self.clear_fault();
         }
     }
 
     pub fn on_key_event(&mut self, event: &KeyEvent) {
+        if self.mirrored {
+            return;
+        }
+
+        match event {
+            KeyEvent::KeyDown { key } | KeyEvent::KeyUp { key }
+                if *key == self.service_key || *key == self.aux_key => {
+                let down = matches!(event, KeyEvent::KeyDown { .. });
+                self.log_event(EventKind::Key { key: *key, down });
+            },
+            _ => {}
+        }
+
         match event {
             KeyEvent::KeyDown { key } if *key == self.service_key => {
+                self.service_down_at = Some(self.clock.now());
                 self.advance_state();
             },
             KeyEvent::KeyUp { key } if *key == self.service_key => {
+                self.service_down_at = None;
                 self.update = true;
             },
             KeyEvent::KeyDown { key } if *key == self.aux_key => {
                 self.key_colors[1] = Colors::Blue.as_rgb();
                 self.update = true;
+                self.authorize();
             },
             KeyEvent::KeyUp { key } if *key == self.aux_key => {
                 self.key_colors[1] = Colors::Black.as_rgb();
@@ -80,26 +410,149 @@ impl <'a>EVCharger<'a> {
         UnitState::from(self.registers.current_state)
     }
 
+    fn log_event(&mut self, kind: EventKind) {
+        let now = self.clock.uptime_ms();
+        self.events.push(now, kind);
+    }
+
+    /// Update the current state register, logging state transitions and faults
+    fn set_current_state(&mut self, value: u16) {
+        let old = self.registers.current_state;
+        if old == value {
+            return;
+        }
+
+        self.registers.current_state = value;
+        self.log_event(EventKind::StateChange { from: old, to: value });
+
+        let error = UnitState::from(value).error;
+        if error != UnitState::from(old).error && error != ErrState::Norminal {
+            self.log_event(EventKind::Fault { error: error.to() });
+        }
+    }
+
+    /// The service key plugs the vehicle in or out
     pub fn advance_state(&mut self) {
-        match self.get_state() {
-            unit  if unit.charger == ChgState::Standby => {
-                self.registers.current_state = 0x8103;
-                self.update = true;
-            },
-            unit  if unit.charger == ChgState::Connect => {
-                self.registers.current_state = 0x8104;
-                self.update = true;
-            },
-            unit  if unit.charger == ChgState::Charge => {
-                self.registers.current_state = 0x8002;
+        match self.get_state().charger {
+            ChgState::Wait | ChgState::Standby => { self.plug_in(); },
+            unit if unit == ChgState::Connect || unit.in_session() => { self.unplug(); },
+            _ => {}
+        }
+    }
+
+    /// The aux key presents an RFID card, authorising a waiting vehicle under the RFID policy
+    pub fn authorize(&mut self) {
+        if self.start_policy == StartPolicy::Rfid && self.get_state().charger == ChgState::Connect {
+            self.start_session();
+        }
+    }
+
+    fn plug_in(&mut self) {
+        self.enter_state(ChgState::Connect, true);
+        self.apply_start_policy();
+    }
+
+    /// Start a session for a connected vehicle unless the policy waits for the master or a card
+    fn apply_start_policy(&mut self) {
+        match self.start_policy {
+            StartPolicy::AutoStart => { self.start_session(); },
+            StartPolicy::MasterEnable if self.is_enabled() => { self.start_session(); },
+            _ => {},
+        };
+    }
+
+    /// Lock the connector, charging starts from Connect once it reports locked
+    fn start_session(&mut self) {
+        self.charge_sec = 0;
+        self.session_wh = 0;
+        self.start_pending = true;
+        if self.lock != LockState::Locked {
+            self.move_lock(LockState::Locking);
+        }
+    }
+
+    fn move_lock(&mut self, lock: LockState) {
+        self.lock = lock;
+        self.lock_until = self.clock.now() + LOCK_TIME.millis();
+        self.update = true;
+    }
+
+    /// Complete lock movements, start a pending session and unlock once the session is over
+    fn update_lock(&mut self) {
+        match self.lock {
+            LockState::Locking | LockState::Unlocking if self.clock.now() >= self.lock_until => {
+                self.lock = match self.lock {
+                    LockState::Locking if self.lock_fault => { LockState::Failed },
+                    LockState::Locking => { LockState::Locked },
+                    _ => { LockState::Unlocked },
+                };
                 self.update = true;
             },
             _ => {}
         }
+
+        let state = self.get_state().charger;
+        if self.start_pending {
+            match self.lock {
+                LockState::Locked => {
+                    self.start_pending = false;
+                    if state == ChgState::Connect {
+                        self.enter_state(ChgState::Charge, true);
+                    }
+                },
+                LockState::Failed => {
+                    self.start_pending = false;
+                    self.log_event(EventKind::LockFailed);
+                },
+                _ => {}
+            }
+        } else if !state.in_session() && (self.lock == LockState::Locked || self.lock == LockState::Locking) {
+            self.move_lock(LockState::Unlocking);
+        }
+    }
+
+    fn unplug(&mut self) {
+        self.vehicle_paused = false;
+        self.start_pending = false;
+        let next = self.idle_state();
+        self.enter_state(next, false);
+    }
+
+    /// State of a unit without a vehicle, Standby once the master has enabled it
+    fn idle_state(&self) -> ChgState {
+        match self.start_policy {
+            StartPolicy::MasterEnable if self.is_enabled() => { ChgState::Standby },
+            _ => { ChgState::Wait },
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.registers.charge_control == 0x0001
+    }
+
+    /// Follow CHARGE_CONTROL when the master governs charging, safe to repeat
+    fn apply_charge_control(&mut self) {
+        match (self.get_state().charger, self.is_enabled()) {
+            (ChgState::Wait, true) => { self.enter_state(ChgState::Standby, false); },
+            (ChgState::Standby, false) => { self.enter_state(ChgState::Wait, false); },
+            (ChgState::Connect, true) if !self.start_pending => { self.start_session(); },
+            (unit, false) if unit.in_session() => { self.enter_state(ChgState::Connect, true); },
+            _ => {}
+        }
+    }
+
+    /// Move to a new charger state, keeping the error bits and flagging the change
+    fn enter_state(&mut self, charger: ChgState, connected: bool) {
+        let mut state = self.get_state();
+        state.charger = charger;
+        state.connected = connected;
+        state.changed = true;
+        self.set_current_state(state.to());
+        self.update = true;
     }
 
     fn update_led_status(&self, light_ports: &mut LightPorts){
-        match self.get_state() {
+        match self.ui_state() {
             unit  if unit.charger == ChgState::Wait => {
                 light_ports.set_bar(self.ui_bank, Colors::Green.as_rgb(), false).unwrap();
                 light_ports.set_button(self.ui_bank, 0, Colors::Black.as_rgb(), true).unwrap();
@@ -116,12 +569,24 @@ impl <'a>EVCharger<'a> {
                 light_ports.set_bar(self.ui_bank, Colors::Orange.as_rgb(), false).unwrap();
                 light_ports.set_button(self.ui_bank, 0, Colors::Orange.as_rgb(), false).unwrap();
             },
+            unit  if unit.charger == ChgState::SuspendedEV => {
+                light_ports.set_bar(self.ui_bank, Colors::Cyan.as_rgb(), false).unwrap();
+                light_ports.set_button(self.ui_bank, 0, Colors::Orange.as_rgb(), false).unwrap();
+            },
+            unit  if unit.charger == ChgState::SuspendedEVSE => {
+                light_ports.set_bar(self.ui_bank, Colors::Blue.as_rgb(), true).unwrap();
+                light_ports.set_button(self.ui_bank, 0, Colors::Orange.as_rgb(), false).unwrap();
+            },
+            unit  if unit.charger == ChgState::Abnormal => {
+                light_ports.set_bar(self.ui_bank, Colors::Red.as_rgb(), true).unwrap();
+                light_ports.set_button(self.ui_bank, 0, Colors::Red.as_rgb(), false).unwrap();
+            },
             _ => {}
         }
     }
 
     fn refresh_display(&mut self, display: &mut TM1638){
-        match self.get_state() {
+        match self.ui_state() {
             unit  if unit.charger == ChgState::Wait => {
                 display.display_num(self.ui_bank, self.unit_id);
             },
@@ -132,12 +597,21 @@ impl <'a>EVCharger<'a> {
                 display.display_num(self.ui_bank, self.charge_sec);
             },
             unit  if unit.charger == ChgState::Charge => {
-                if self.sys_timer.now() > self.charge_next {
-                    self.charge_next = self.sys_timer.now() + SECOND.millis();
-                    self.charge_sec += 1;
+                if self.clock.now() > self.charge_next {
+                    self.charge_next = self.clock.now() + SECOND.millis();
+                    // the local session time is kept for when mirroring ends
+                    if !self.mirrored {
+                        self.charge_sec += 1;
+                    }
                 }
                 display.display_num(self.ui_bank, self.charge_sec);
             },
+            unit  if unit.charger.in_session() => {
+                display.display_num(self.ui_bank, self.charge_sec);
+            },
+            unit  if unit.charger == ChgState::Abnormal => {
+                display.display_num(self.ui_bank, self.unit_id);
+            },
             _ => {}
         }
 
@@ -145,7 +619,7 @@ impl <'a>EVCharger<'a> {
 
     pub fn refresh_ui(&mut self, display: &mut TM1638, light_ports: &mut LightPorts) -> bool {
         if self.update ||
-           (self.get_state().charger == ChgState::Charge && self.sys_timer.now() > self.charge_next){
+           (self.ui_state().charger == ChgState::Charge && self.clock.now() > self.charge_next){
             self.refresh_display(display);
 
             self.update_led_status(light_ports);
@@ -159,45 +633,220 @@ impl <'a>EVCharger<'a> {
         false
     }
 
-    pub fn query(&mut self, request: &ModbusFrame ) -> Result<ModbusFrame, &str> {
+    pub fn query(&mut self, request: &ModbusFrame ) -> Result<ModbusFrame, Refusal> {
 
-        if request.unit_id != self.unit_id {return Err("not for this unit")};
+        if request.unit_id != self.unit_id {return Err(Refusal::NotForThisUnit)};
 
         let addr = match request.refers {
             Reference::Address(addr) => {addr},
-            _ => {return Err("invalid query")}
+            _ => {return Err(Refusal::IllegalAddress)}
         };
 
-        match (request.command, addr) {
-            (4, CURRENT_STATE)  => { Ok(request.read_reply(self.registers.current_state)) },
-            (3, CHARGE_CONTROL)  => { Ok(request.read_reply(self.registers.charge_control)) },
-            (3, SERVICE_CONTROL)  => { Ok(request.read_reply(self.registers.service_control)) },
-            (6, CHARGE_CONTROL)  => {
-                if self.registers.charge_control != request.value {
-                    let mut state = UnitState::from(self.registers.current_state);
-                    state.set_charge_control(request.value);
-                    state.changed = true;
-                    self.registers.current_state = state.to();
-                    self.charge_sec = 0;
+        match request.command {
+            3 => { self.read_block(request, addr, Self::read_holding) },
+            4 => { self.read_block(request, addr, Self::read_input) },
+            6 => { self.write_holding(addr, request.value).map(|value| request.write_reply(value)) },
+            _ => { Err(Refusal::IllegalFunction) }
+        }
+
+    }
+
+    /// Read `request.value` consecutive registers starting at `addr`
+    fn read_block(&self,
+                  request: &ModbusFrame,
+                  addr: u16,
+                  read: fn(&Self, u16) -> Result<u16, Refusal>) -> Result<ModbusFrame, Refusal> {
+        let count = request.value as usize;
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(Refusal::IllegalValue);
+        }
+
+        let mut values: Vec<u16, MAX_REGISTERS> = Vec::new();
+        for i in 0..count {
+            let _ = values.push(read(self, addr.wrapping_add(i as u16))?);
+        }
+
+        Ok(request.read_reply_regs(&values))
+    }
+
+    fn read_input(&self, addr: u16) -> Result<u16, Refusal> {
+        match addr {
+            UPTIME_HI => { Ok(((self.clock.uptime_ms() / 1000) >> 16) as u16) },
+            UPTIME_LO => { Ok((self.clock.uptime_ms() / 1000) as u16) },
+            CURRENT_STATE => { Ok(self.registers.current_state) },
+            CURRENT_L1 => { Ok(self.phase_current(0)) },
+            CURRENT_L2 => { Ok(self.phase_current(1)) },
+            CURRENT_L3 => { Ok(self.phase_current(2)) },
+            ACTIVE_PHASES => { Ok(self.active_phases() as u16) },
+            OFFERED_CURRENT => { Ok(self.offered_current()) },
+            TEMPERATURE => { Ok((self.temperature * 10.0) as i16 as u16) },
+            RCD_TRIPS => { Ok(self.rcd_trips) },
+            LOCK_STATUS => { Ok(self.lock.to()) },
+            LIFETIME_ENERGY_HI => { Ok((self.lifetime_wh >> 16) as u16) },
+            LIFETIME_ENERGY_LO => { Ok(self.lifetime_wh as u16) },
+            SESSION_ENERGY_HI => { Ok((self.session_wh >> 16) as u16) },
+            SESSION_ENERGY_LO => { Ok(self.session_wh as u16) },
+            _ => { Err(Refusal::IllegalAddress) }
+        }
+    }
+
+    fn read_holding(&self, addr: u16) -> Result<u16, Refusal> {
+        match addr {
+            EPOCH_TIME_HI => { Ok((self.clock.epoch().unwrap_or(0) >> 16) as u16) },
+            EPOCH_TIME_LO => { Ok(self.clock.epoch().unwrap_or(0) as u16) },
+            CHARGE_CONTROL => { Ok(self.registers.charge_control) },
+            SERVICE_CONTROL => { Ok(self.registers.service_control) },
+            PHASE_SWITCH => { Ok(self.registers.phase_switch) },
+            MAX_CURRENT => { Ok(self.registers.max_current) },
+            AMBIENT_TEMPERATURE => { Ok(self.registers.ambient) },
+            _ => { Err(Refusal::IllegalAddress) }
+        }
+    }
+
+    fn write_holding(&mut self, addr: u16, value: u16) -> Result<u16, Refusal> {
+        let old = self.read_holding(addr)?;
+
+        // while locked out by a residual current trip only the reset sequence is honoured,
+        // CHARGE_CONTROL writes are acknowledged and ignored
+        if self.rcd_latched {
+            match addr {
+                CHARGE_CONTROL => { return Ok(value) },
+                SERVICE_CONTROL => { return Ok(self.write_rcd_reset(value)) },
+                _ => {}
+            }
+        }
+
+        match addr {
+            MAX_CURRENT if value > MAX_CURRENT_LIMIT => { return Err(Refusal::IllegalValue) },
+            PHASE_SWITCH if !(value == 1 || value == 3) => { return Err(Refusal::IllegalValue) },
+            // a single phase charger can not switch to three phases
+            PHASE_SWITCH if value == 3 && self.charger_phases != 3 => { return Err(Refusal::IllegalValue) },
+            AMBIENT_TEMPERATURE if !(-400..=800).contains(&(value as i16)) => { return Err(Refusal::IllegalValue) },
+            _ => {}
+        }
+
+        if old != value {
+            self.log_event(EventKind::RegisterWrite { addr, old, new: value });
+        }
+
+        match addr {
+            CHARGE_CONTROL => {
+                self.registers.charge_control = value;
+                // auto start and RFID units keep the value but do not hand control to the master,
+                // a repeated enable restarts a unit that was held up by a fault or outage
+                if self.start_policy == StartPolicy::MasterEnable {
+                    self.apply_charge_control();
                 }
-                self.registers.charge_control = request.value;
-                self.update = true;
-                Ok(request.write_reply(self.registers.charge_control))
             },
-            (6, SERVICE_CONTROL)  => {
-                if self.registers.service_control != request.value {
+            SERVICE_CONTROL => {
+                if old != value {
                     let mut state = UnitState::from(self.registers.current_state);
-                    state.set_service_control(request.value);
+                    state.set_service_control(value);
                     state.changed = true;
-                    self.registers.current_state = state.to();
+                    self.set_current_state(state.to());
                 }
-                self.registers.service_control = request.value;
-                self.update = true;
-                Ok(request.write_reply(self.registers.service_control))
+                self.registers.service_control = value;
+                if old != value && self.start_policy == StartPolicy::MasterEnable {
+                    self.apply_charge_control();
+                }
+            },
+            PHASE_SWITCH => {
+                if old != value {
+                    self.switch_until = Some(self.clock.now() + PHASE_SWITCH_PAUSE.millis());
+                }
+                self.registers.phase_switch = value;
+            },
+            MAX_CURRENT => {
+                self.registers.max_current = value;
+            },
+            AMBIENT_TEMPERATURE => {
+                self.registers.ambient = value;
             },
-            _ => {return Err("invalid operation")}
+            EPOCH_TIME_HI => {
+                // held until the low word completes the time
+                self.epoch_hi = value;
+            },
+            EPOCH_TIME_LO => {
+                self.clock.set_epoch(((self.epoch_hi as u32) << 16) | value as u32);
+            },
+            _ => {}
+        }
+
+        self.update = true;
+        Ok(value)
+    }
+
+    fn write_rcd_reset(&mut self, value: u16) -> u16 {
+        self.log_event(EventKind::RegisterWrite { addr: SERVICE_CONTROL, old: self.registers.service_control, new: value });
+        self.registers.service_control = value;
+
+        if value == RCD_RESET_CONFIRM && self.rcd_reset_armed {
+            self.reset_rcd();
+        } else {
+            self.rcd_reset_armed = value == RCD_RESET_ARM;
+        }
+        value
+    }
+
+    /// Number of phases currently carrying current to the vehicle
+    fn active_phases(&self) -> u8 {
+        let selected = self.registers.phase_switch as u8;
+        if selected < self.vehicle_phases { selected } else { self.vehicle_phases }
+    }
+
+    /// Current drawn on a given phase (0 - 2) in units of 0.1 A
+    fn phase_current(&self, phase: u8) -> u16 {
+        if self.get_state().charger != ChgState::Charge || self.switch_until.is_some() {
+            return 0;
+        }
+
+        if phase < self.active_phases() { self.offered_current() } else { 0 }
+    }
+
+    /// Current offered to the vehicle in units of 0.1 A, derated linearly to DERATE_FLOOR
+    /// between DERATE_START and DERATE_END
+    fn offered_current(&self) -> u16 {
+        let max_current = self.registers.max_current;
+        let floor = max_current.min(DERATE_FLOOR);
+        if self.temperature <= DERATE_START {
+            return max_current;
         }
+        if self.temperature >= DERATE_END {
+            return floor;
+        }
+
+        let scale = (DERATE_END - self.temperature) / (DERATE_END - DERATE_START);
+        floor + ((max_current - floor) as f32 * scale) as u16
+    }
+
+    pub fn start_policy(&self) -> StartPolicy {
+        self.start_policy
+    }
+
+    pub fn set_start_policy(&mut self, policy: StartPolicy) {
+        self.start_policy = policy;
+        self.update = true;
+    }
+
+    /// Time the unit takes to answer on the bus
+    pub fn response_delay(&self) -> ResponseDelay {
+        self.response_delay
+    }
+
+    pub fn set_response_delay(&mut self, delay: ResponseDelay) {
+        self.response_delay = delay;
+    }
+
+    pub fn charger_state(&self) -> ChgState {
+        self.get_state().charger
+    }
 
+    pub fn service_key(&self) -> u8 {
+        self.service_key
+    }
+
+    pub fn aux_key(&self) -> u8 {
+        self.aux_key
     }
 
     pub fn get_id(&self) -> u8{
@@ -207,12 +856,53 @@ impl <'a>EVCharger<'a> {
     pub fn set_id(&mut self, new_id: u8) -> u8{
         self.unit_id = new_id;
         self.update = true;
-        self.registers.current_state = 0x0001;
+        self.set_current_state(0x0001);
+        self.rcd_latched = false;
+        self.rcd_reset_armed = false;
         self.registers.charge_control = 0x0000;
         self.registers.service_control = 0x0000;
         self.unit_id
     }
 
+    /// Configure the phase wiring of the charger and the vehicle plugged into it
+    ///
+    /// * `charger_phases` - 1 or 3 phase charger.
+    /// * `vehicle_phases` - 1 through 3 phases drawn by the vehicle.
+    pub fn set_phases(&mut self, charger_phases: u8, vehicle_phases: u8) -> Result<(), &'static str> {
+        if !(charger_phases == 1 || charger_phases == 3) || !(1..=3).contains(&vehicle_phases) {
+            return Err("invalid phase count");
+        }
+
+        self.charger_phases = charger_phases;
+        self.vehicle_phases = vehicle_phases;
+        self.registers.phase_switch = charger_phases as u16;
+        self.switch_until = None;
+        self.update = true;
+        Ok(())
+    }
+
+    /// Simulate the vehicle stopping (or resuming) its draw without ending the session
+    pub fn set_vehicle_paused(&mut self, paused: bool) {
+        self.vehicle_paused = paused;
+        self.update = true;
+    }
+
+    pub fn vehicle_paused(&self) -> bool {
+        self.vehicle_paused
+    }
+
+    /// Returns (charger phases, vehicle phases, active phases)
+    pub fn get_phases(&self) -> (u8, u8, u8) {
+        (self.charger_phases, self.vehicle_phases, self.active_phases())
+    }
+
+    pub fn events(&self) -> &EventLog {
+        &self.events
+    }
+
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
 
 }
 
@@ -228,6 +918,8 @@ pub enum ChgState{
     Abnormal,
     Stop,
     Reboot,
+    SuspendedEV,
+    SuspendedEVSE,
     Unknown,
 }
 
@@ -243,6 +935,8 @@ impl ChgState{
             0x06 => { Self::Abnormal },
             0x07 => { Self::Stop },
             0x08 => { Self::Reboot },
+            0x09 => { Self::SuspendedEV },
+            0x0a => { Self::SuspendedEVSE },
             _ => { Self::Unknown },
         }
     }
@@ -258,10 +952,82 @@ impl ChgState{
             Self::Abnormal => { 0x06 },
             Self::Stop => { 0x07 },
             Self::Reboot => { 0x08 },
+            Self::SuspendedEV => { 0x09 },
+            Self::SuspendedEVSE => { 0x0a },
             _ => { 0x0f },
         }
     }
 
+    /// True while a session is under way, charging or suspended
+    pub fn in_session(&self) -> bool {
+        matches!(self, Self::Charge | Self::SuspendedEV | Self::SuspendedEVSE)
+    }
+
+}
+
+/// Position of the emulated connector lock actuator
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum LockState{
+    Unlocked,
+    Locking,
+    Locked,
+    Unlocking,
+    Failed,
+}
+
+impl LockState{
+    fn to(&self) -> u16{
+        match self {
+            Self::Unlocked => { 0x00 },
+            Self::Locking => { 0x01 },
+            Self::Locked => { 0x02 },
+            Self::Unlocking => { 0x03 },
+            Self::Failed => { 0x04 },
+        }
+    }
+}
+
+/// How a unit decides to start charging once a vehicle is plugged in
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum StartPolicy{
+    MasterEnable,
+    AutoStart,
+    Rfid,
+}
+
+impl StartPolicy{
+    pub fn from(value: u8) -> Self{
+        match value {
+            0x01 => { Self::AutoStart },
+            0x02 => { Self::Rfid },
+            _ => { Self::MasterEnable },
+        }
+    }
+
+    pub fn to(&self) -> u8{
+        match self {
+            Self::MasterEnable => { 0x00 },
+            Self::AutoStart => { 0x01 },
+            Self::Rfid => { 0x02 },
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self>{
+        match name {
+            "master" => { Some(Self::MasterEnable) },
+            "auto" => { Some(Self::AutoStart) },
+            "rfid" => { Some(Self::Rfid) },
+            _ => { None },
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        match self {
+            Self::MasterEnable => { "master" },
+            Self::AutoStart => { "auto" },
+            Self::Rfid => { "rfid" },
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -272,6 +1038,7 @@ pub enum ErrState{
     ErrTemperature,
     ErrRelay,
     ErrCplt,
+    ErrResidual,
     ErrUnknown,
 }
 
@@ -284,6 +1051,7 @@ impl ErrState{
             0x030 => { Self::ErrTemperature },
             0x040 => { Self::ErrRelay },
             0x050 => { Self::ErrCplt },
+            0x060 => { Self::ErrResidual },
             _ => { Self::ErrUnknown },
         }
     }
@@ -295,6 +1063,7 @@ impl ErrState{
             Self::ErrTemperature => { 0x030 },
             Self::ErrRelay => { 0x040 },
             Self::ErrCplt => { 0x050 },
+            Self::ErrResidual => { 0x060 },
             _ => { 0x0f0 },
         }
     }
@@ -325,13 +1094,6 @@ impl UnitState{
         value
     }
 
-    fn set_charge_control(&mut self, value: u16){
-        match value{
-            0x0001 => {self.charger = ChgState::Standby},
-            _ => {self.charger = ChgState::Wait}
-        }
-    }
-
     fn set_service_control(&mut self, value: u16){
         match value{
             0x0001 => {self.charger = ChgState::Outage},
@@ -0,0 +1,107 @@
+use core::fmt;
+
+pub const EVENT_LOG_LEN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EventKind {
+    StateChange{from: u16, to: u16},
+    Fault{error: u16},
+    RegisterWrite{addr: u16, old: u16, new: u16},
+    Key{key: u8, down: bool},
+    LockFailed,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Event {
+    /// uptime in msec
+    pub time: u64,
+    pub kind: EventKind,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:>10} {}", self.time, self.kind)
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            EventKind::StateChange { from, to } => {
+                write!(f, "state 0x{:04x} -> 0x{:04x}", from, to)
+            },
+            EventKind::Fault { error } => {
+                write!(f, "fault 0x{:03x}", error)
+            },
+            EventKind::RegisterWrite { addr, old, new } => {
+                write!(f, "write 0x{:04x}: 0x{:04x} -> 0x{:04x}", addr, old, new)
+            },
+            EventKind::Key { key, down } => {
+                write!(f, "key {} {}", key, if down {"down"} else {"up"})
+            },
+            EventKind::LockFailed => {
+                write!(f, "connector lock failed")
+            },
+        }
+    }
+}
+
+/// Ring buffer of the most recent events of a single unit
+///
+/// once full, the oldest event is overwritten and the overflow counter is
+/// incremented so lost history can be detected by the reader
+pub struct EventLog {
+    events: [Option<Event>; EVENT_LOG_LEN],
+    head: usize,
+    len: usize,
+    overflows: u32,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self {
+            events: [None; EVENT_LOG_LEN],
+            head: 0,
+            len: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Record an event, overwriting the oldest one if the log is full
+    ///
+    /// * `time` - uptime (msec) at which the event occured.
+    /// * `kind` - what happened.
+    pub fn push(&mut self, time: u64, kind: EventKind) {
+        self.events[self.head] = Some(Event { time, kind });
+        self.head = (self.head + 1) % EVENT_LOG_LEN;
+
+        if self.len < EVENT_LOG_LEN {
+            self.len += 1;
+        } else {
+            self.overflows = self.overflows.wrapping_add(1);
+        }
+    }
+
+    /// Discard all events and reset the overflow counter
+    pub fn clear(&mut self) {
+        self.events = [None; EVENT_LOG_LEN];
+        self.head = 0;
+        self.len = 0;
+        self.overflows = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Number of events lost since the log was last cleared
+    pub fn overflows(&self) -> u32 {
+        self.overflows
+    }
+
+    /// Iterate over the logged events from oldest to newest
+    pub fn iter(&self) -> impl Iterator<Item = &Event> + '_ {
+        let start = (self.head + EVENT_LOG_LEN - self.len) % EVENT_LOG_LEN;
+        (0..self.len).filter_map(move |i| self.events[(start + i) % EVENT_LOG_LEN].as_ref())
+    }
+}
@@ -0,0 +1,206 @@
+use heapless::{LinearMap, Vec};
+
+use crate::modbus::*;
+use crate::prng::Prng;
+
+pub const FAULT_KINDS: usize = 7;
+
+// how long a late reply is held back, well beyond any sensible master timeout
+pub const LATE_REPLY_US: u32 = 2_000_000;
+
+// exception codes picked from for injected exception replies
+const EXCEPTION_CODES: [u8; 5] = [0x01, 0x02, 0x03, 0x04, 0x06];
+
+/// Transport level fault that can be injected into a reply
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    Drop,
+    Late,
+    BadCrc,
+    Truncate,
+    WrongUnit,
+    WrongFunction,
+    Exception,
+}
+
+impl Fault {
+    pub const ALL: [Fault; FAULT_KINDS] = [
+        Fault::Drop,
+        Fault::Late,
+        Fault::BadCrc,
+        Fault::Truncate,
+        Fault::WrongUnit,
+        Fault::WrongFunction,
+        Fault::Exception,
+    ];
+
+    pub fn index(&self) -> usize {
+        match self {
+            Self::Drop => { 0 },
+            Self::Late => { 1 },
+            Self::BadCrc => { 2 },
+            Self::Truncate => { 3 },
+            Self::WrongUnit => { 4 },
+            Self::WrongFunction => { 5 },
+            Self::Exception => { 6 },
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|fault| fault.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Drop => { "drop" },
+            Self::Late => { "late" },
+            Self::BadCrc => { "crc" },
+            Self::Truncate => { "truncate" },
+            Self::WrongUnit => { "unit" },
+            Self::WrongFunction => { "fc" },
+            Self::Exception => { "exception" },
+        }
+    }
+}
+
+/// Probability in percent of each fault for one unit
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FaultConfig {
+    percent: [u8; FAULT_KINDS],
+}
+
+impl FaultConfig {
+    pub fn get(&self, fault: Fault) -> u8 {
+        self.percent[fault.index()]
+    }
+
+    pub fn set(&mut self, fault: Fault, percent: u8) {
+        self.percent[fault.index()] = percent.min(100);
+    }
+
+    pub fn is_clear(&self) -> bool {
+        self.percent.iter().all(|&p| p == 0)
+    }
+}
+
+/// What became of a reply after fault injection
+pub enum Injected {
+    /// send the (possibly mangled) reply after the extra delay in usec
+    Send(u32),
+    /// do not answer at all
+    Dropped,
+}
+
+/// Per unit fault configuration of the bus and counters of the faults injected
+pub struct FaultInjector {
+    units: LinearMap<u8, FaultConfig, 8>,
+    counts: [u32; FAULT_KINDS],
+    rng: Prng,
+}
+
+impl FaultInjector {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            units: LinearMap::new(),
+            counts: [0; FAULT_KINDS],
+            rng: Prng::new(seed),
+        }
+    }
+
+    pub fn config(&self, unit_id: u8) -> FaultConfig {
+        self.units.get(&unit_id).copied().unwrap_or_default()
+    }
+
+    /// Set the probability of a fault for a unit, 0 disables it
+    pub fn set(&mut self, unit_id: u8, fault: Fault, percent: u8) -> Result<(), &'static str> {
+        let mut config = self.config(unit_id);
+        config.set(fault, percent);
+
+        if config.is_clear() {
+            self.units.remove(&unit_id);
+        } else if self.units.insert(unit_id, config).is_err() {
+            return Err("too many units");
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.units.clear();
+    }
+
+    /// Units with any fault configured
+    pub fn units(&self) -> impl Iterator<Item = (&u8, &FaultConfig)> {
+        self.units.iter()
+    }
+
+    /// Number of times each fault was injected, indexed by `Fault::index`
+    pub fn counts(&self) -> &[u32; FAULT_KINDS] {
+        &self.counts
+    }
+
+    /// Roll the dice for each configured fault and apply them to the encoded reply in `adu`
+    pub fn inject(&mut self, adu: &mut Vec<u8, MAX_ADU_LEN>) -> Injected {
+        let unit_id = match adu.first() {
+            Some(&id) => { id },
+            None => { return Injected::Send(0) },
+        };
+        let config = match self.units.get(&unit_id) {
+            Some(config) => { *config },
+            None => { return Injected::Send(0) },
+        };
+
+        if self.roll(&config, Fault::Drop) {
+            return Injected::Dropped;
+        }
+
+        let mut reseal = false;
+        if self.roll(&config, Fault::Exception) {
+            let code = EXCEPTION_CODES[self.rng.range(0, EXCEPTION_CODES.len() as u32 - 1) as usize];
+            let function = adu[1] | 0x80;
+            adu.truncate(1);
+            let _ = adu.extend_from_slice(&[function, code, 0, 0]);
+            reseal = true;
+        }
+        if self.roll(&config, Fault::WrongUnit) {
+            adu[0] = unit_id.wrapping_add(1);
+            reseal = true;
+        }
+        if self.roll(&config, Fault::WrongFunction) {
+            adu[1] ^= 0x01;
+            reseal = true;
+        }
+        if reseal {
+            Self::reseal(adu);
+        }
+
+        if self.roll(&config, Fault::BadCrc) {
+            let last = adu.len() - 1;
+            adu[last] ^= 0xff;
+        }
+        if self.roll(&config, Fault::Truncate) {
+            let len = self.rng.range(1, adu.len() as u32 - 1);
+            adu.truncate(len as usize);
+        }
+
+        if self.roll(&config, Fault::Late) {
+            return Injected::Send(LATE_REPLY_US);
+        }
+        Injected::Send(0)
+    }
+
+    fn roll(&mut self, config: &FaultConfig, fault: Fault) -> bool {
+        let hit = self.rng.chance(config.get(fault));
+        if hit {
+            self.counts[fault.index()] += 1;
+        }
+        hit
+    }
+
+    /// Replace the crc of an edited frame, so only the intended fault is visible
+    fn reseal(adu: &mut Vec<u8, MAX_ADU_LEN>) {
+        let len = adu.len() - 2;
+        let crc = ModbusFrame::calculate_crc16(&adu[..len]);
+        adu[len] = crc as u8;
+        adu[len + 1] = (crc >> 8) as u8;
+    }
+}
@@ -26,6 +26,11 @@ use test_points::{*};
 mod ev_charger;
 use ev_charger::*;
 
+mod event_log;
+
+mod clock;
+use clock::*;
+
 mod display;
 use display::*;
 
@@ -44,12 +49,35 @@ use modbus::*;
 mod usb;
 use usb::*;
 
+mod prng;
+
+mod soak;
+use soak::*;
+
+mod settings;
+
+mod storage;
+use storage::*;
+
+mod site;
+use site::*;
+
+mod fault;
+
+mod monitor;
+
+mod capture;
+
+mod mirror;
+use mirror::Mirror;
+
+// lifetime energy meters are written to flash at most this often
+const ENERGY_SAVE_PERIOD: u32 = 10 * 60 * 1000;
+
 #[entry]
 fn main() -> ! {
     rtt_init_print!();
 
-    19200.bps();
-
     // Acquire the device peripherals
     let dp = pac::Peripherals::take().unwrap();
 
@@ -81,16 +109,39 @@ fn main() -> ! {
     let mut buffer = [0u8; (LED_NUM * 12) + 30];
     let mut lights = LightPorts::new(gpioa.pa5, gpioa.pa7, dp.SPI1, &mut buffer, &clocks, &sys_timer);
 
+    // Extended uptime and wall clock time
+    let clock = Clock::new(&sys_timer);
+
     // Initialize the bank of EvCharger units
     let mut chargers: [EVCharger; 4] = [
-        EVCharger::new(1, 0, &sys_timer),
-        EVCharger::new(2, 1, &sys_timer),
-        EVCharger::new(3, 2, &sys_timer),
-        EVCharger::new(4, 3, &sys_timer),
+        EVCharger::new(1, 0, &clock),
+        EVCharger::new(2, 1, &clock),
+        EVCharger::new(3, 2, &clock),
+        EVCharger::new(4, 3, &clock),
     ];
 
-    // Initialize Modbus interface
-    let mut modbus = ModbusTransceiver::new(gpioa.pa2, gpioa.pa3, gpioa.pa4, dp.USART2, dp.DMA1, &clocks, &sys_timer);
+    // Restore the persisted configuration
+    let mut storage = Storage::new(dp.FLASH);
+    storage.settings().apply(&mut chargers);
+    for (chrg, wh) in chargers.iter_mut().zip(storage.energy()) {
+        chrg.set_lifetime_energy(wh);
+    }
+    let mut energy_save_next = sys_timer.now() + ENERGY_SAVE_PERIOD.millis();
+
+    // Initialize the optional site aggregate unit
+    let mut site = SiteUnit::new(storage.settings().site_id);
+
+    // Initialize the randomised soak test driver
+    let mut soak = SoakDriver::new(&sys_timer);
+
+    // Initialize the mirroring of remote chargers polled as bus master
+    let mut mirror = Mirror::new(&sys_timer);
+
+    // Initialize Modbus interface, timing frames with a free running usec counter
+    let mut us_timer = dp.TIM5.counter_us(&clocks);
+    us_timer.start(u32::MAX.micros()).unwrap();
+    let mut modbus = ModbusTransceiver::new(gpioa.pa2, gpioa.pa3, gpioa.pa4, dp.USART2, dp.DMA1,
+                                            &storage.settings().serial, &clocks, us_timer);
 
     // Initialize the USBdevice as a serial adaptor
     let usb = USB {
@@ -119,9 +170,13 @@ fn main() -> ! {
     rprintln!("USB Built");
 
     loop {
+        // keep the uptime extension tracking sys_timer wraps
+        clock.uptime_ms();
+
         // refresh the UI for each charger
         let mut updated = false;
         for chrg in &mut chargers {
+            chrg.tick();
             if chrg.refresh_ui(&mut display, &mut lights) {
                 updated = true;
             }
@@ -145,17 +200,24 @@ fn main() -> ! {
         {modbus.scan_rx_msg(&mut chargers,
                             |msg: &ModbusFrame, chargers: &mut [EVCharger; 4] | {
             rprintln!("--> on_receive: {:?}", msg);
-            for chrg in chargers{
-                match chrg.query(msg) {
-                    Ok(reply) => {return Some(reply);},
-                    _ => {}
-                }
-            }
-            None
+            answer_local(msg, &mut site, chargers).ok()
         });}
 
+        //  poll the remote units shown on mirrored ui banks
+        mirror.run(&mut modbus, &mut chargers);
+
+        //  drive the units when soak testing
+        soak.run(&mut chargers);
+
+        //  persist the lifetime energy meters
+        if sys_timer.now() >= energy_save_next {
+            energy_save_next = sys_timer.now() + ENERGY_SAVE_PERIOD.millis();
+            storage.save_energy(&chargers)
+            .unwrap_or_else(|err| {rprintln!("energy save failed: {:?}", err); });
+        }
+
         //  process any USB commands
-        usb_processor.poll(&mut chargers);
+        usb_processor.poll(&mut chargers, &mut soak, &mut storage, &mut site, &clock, &mut modbus, &mut mirror);
 
 
         // delay 1 msec to reduce overhead
@@ -165,3 +227,15 @@ fn main() -> ! {
 
 
     }}
+
+/// Answer a request addressed to the site aggregate or one of the emulated units, or
+/// report why the addressed unit refused it
+pub fn answer_local(msg: &ModbusFrame, site: &mut SiteUnit, chargers: &mut [EVCharger; 4]) -> Result<ModbusFrame, Refusal> {
+    if site.get_id() != 0 && msg.unit_id == site.get_id() {
+        return site.query(msg, chargers);
+    }
+    match chargers.iter_mut().find(|chrg| chrg.get_id() == msg.unit_id) {
+        Some(chrg) => { chrg.query(msg) },
+        None => { Err(Refusal::NotForThisUnit) },
+    }
+}
@@ -0,0 +1,89 @@
+use crate::hal::pac::TIM2;
+use crate::hal::timer::Counter;
+use crate::hal::prelude::*;
+use fugit::Instant;
+
+use rtt_target::rprintln;
+
+use crate::ev_charger::*;
+use crate::modbus::*;
+use crate::serial::*;
+
+// one mirrored unit is polled per period, in turn
+const POLL_PERIOD: u32 = 250;
+
+/// Shows the state of remote chargers, polled as bus master, on the local UI banks
+pub struct Mirror<'a> {
+    sys_timer: &'a Counter<TIM2, 1000>,
+    /// remote unit id shown on each ui bank
+    remotes: [Option<u8>; 4],
+    failures: [u32; 4],
+    next_poll: Instant<u32, 1, 1000>,
+    cursor: usize,
+}
+
+impl <'a>Mirror<'a> {
+    pub fn new(sys_timer: &'a Counter<TIM2, 1000>) -> Self {
+        Self {
+            sys_timer,
+            remotes: [None; 4],
+            failures: [0; 4],
+            next_poll: sys_timer.now(),
+            cursor: 0,
+        }
+    }
+
+    /// Mirror `remote` on the ui bank of `chargers[index]`, None returns the bank to the local unit
+    pub fn set_remote(&mut self, chargers: &mut [EVCharger; 4], index: usize, remote: Option<u8>) {
+        self.remotes[index] = remote;
+        self.failures[index] = 0;
+        chargers[index].set_mirrored(remote.is_some());
+    }
+
+    pub fn remote(&self, index: usize) -> Option<u8> {
+        self.remotes[index]
+    }
+
+    /// Polls of the remote unit that got no usable reply
+    pub fn failures(&self, index: usize) -> u32 {
+        self.failures[index]
+    }
+
+    pub fn run(&mut self, modbus: &mut ModbusTransceiver, chargers: &mut [EVCharger; 4]) {
+        while let Some(response) = modbus.take_response() {
+            self.on_response(response, chargers);
+        }
+
+        if self.sys_timer.now() < self.next_poll || !modbus.master_idle() {
+            return;
+        }
+        self.next_poll = self.sys_timer.now() + POLL_PERIOD.millis();
+
+        for _ in 0..self.remotes.len() {
+            self.cursor = (self.cursor + 1) % self.remotes.len();
+            if let Some(remote) = self.remotes[self.cursor] {
+                let request = ModbusFrame::new(remote, 4, Reference::Address(CURRENT_STATE), 1);
+                if let Err(err) = modbus.queue_request(request) {
+                    rprintln!("mirror: {}", err);
+                }
+                return;
+            }
+        }
+    }
+
+    fn on_response(&mut self, response: MasterResponse, chargers: &mut [EVCharger; 4]) {
+        for (i, remote) in self.remotes.iter().enumerate() {
+            if *remote != Some(response.request.unit_id) {
+                continue;
+            }
+
+            match &response.result {
+                Ok(values) if !values.is_empty() => { chargers[i].mirror_state(values[0]); },
+                _ => {
+                    self.failures[i] += 1;
+                    rprintln!("mirror: unit {} {:?}", response.request.unit_id, response.result.as_ref().err());
+                },
+            }
+        }
+    }
+}
@@ -1,19 +1,100 @@
 
+use heapless::Vec;
 use rtt_target::rprintln;
 
+// largest register block a single read may return
+pub const MAX_REGISTERS: usize = 125;
 
-#[derive(PartialEq, Debug)]
+// largest RTU frame: unit id, function code, 252 byte PDU payload and crc
+pub const MAX_ADU_LEN: usize = 256;
+
+// largest ASCII frame: ':', the RTU frame less crc plus an LRC as hex, and CR LF
+pub const MAX_ASCII_LEN: usize = 1 + (MAX_ADU_LEN - 1) * 2 + 2;
+
+// Modbus TCP header: transaction id, protocol id, length and unit id
+pub const MBAP_HEADER_LEN: usize = 7;
+
+// largest Modbus TCP frame: the header and a 253 byte PDU
+pub const MAX_MBAP_LEN: usize = MBAP_HEADER_LEN + MAX_ADU_LEN - 3;
+
+/// Modbus TCP application protocol header
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MbapHeader {
+    pub transaction_id: u16,
+    pub protocol_id: u16,
+    /// bytes following the length field, the unit id and the PDU
+    pub length: u16,
+    pub unit_id: u8,
+}
+
+impl MbapHeader {
+    pub fn decode(buffer: &[u8]) -> Option<Self> {
+        if buffer.len() < MBAP_HEADER_LEN {
+            return None;
+        }
+
+        Some(Self {
+            transaction_id: u16::from_be_bytes([buffer[0], buffer[1]]),
+            protocol_id: u16::from_be_bytes([buffer[2], buffer[3]]),
+            length: u16::from_be_bytes([buffer[4], buffer[5]]),
+            unit_id: buffer[6],
+        })
+    }
+
+    pub fn encode(&self, buffer: &mut [u8]) -> usize {
+        buffer[0..2].copy_from_slice(&self.transaction_id.to_be_bytes());
+        buffer[2..4].copy_from_slice(&self.protocol_id.to_be_bytes());
+        buffer[4..6].copy_from_slice(&self.length.to_be_bytes());
+        buffer[6] = self.unit_id;
+
+        MBAP_HEADER_LEN
+    }
+
+    /// Length of the whole frame this header starts
+    pub fn frame_len(&self) -> usize {
+        MBAP_HEADER_LEN - 1 + self.length as usize
+    }
+}
+
+/// Why a unit refused a request
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Refusal {
+    /// no unit with the requested id
+    NotForThisUnit,
+    /// function the unit does not implement
+    IllegalFunction,
+    /// register the unit does not have
+    IllegalAddress,
+    /// quantity or written value out of range
+    IllegalValue,
+}
+
+impl Refusal {
+    /// Exception code answering the refused request
+    pub fn exception_code(&self) -> u8 {
+        match self {
+            // like a tcp gateway whose target did not respond
+            Self::NotForThisUnit => { 0x0b },
+            Self::IllegalFunction => { 0x01 },
+            Self::IllegalAddress => { 0x02 },
+            Self::IllegalValue => { 0x03 },
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum Reference {
     Size(u8),
     Address(u16)
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct ModbusFrame {
     pub unit_id: u8,
     pub command: u8,
     pub refers: Reference,
-    pub value: u16
+    pub value: u16,
+    pub values: Vec<u16, MAX_REGISTERS>,
 }
 
 impl ModbusFrame {
@@ -26,7 +107,8 @@ impl ModbusFrame {
                 unit_id,
                 command,
                 refers,
-                value
+                value,
+                values: Vec::new(),
             }
     }
 
@@ -39,6 +121,18 @@ impl ModbusFrame {
         )
     }
 
+    /// Reply to a read of several consecutive registers
+    pub fn read_reply_regs(&self, values: &[u16]) -> ModbusFrame{
+        let mut reply = ModbusFrame::new(
+            self.unit_id,
+            self.command,
+            Reference::Size((values.len() * 2) as u8),
+            0
+        );
+        reply.values.extend_from_slice(values).unwrap();
+        reply
+    }
+
     pub fn write_reply(&self, value: u16) -> ModbusFrame{
         let addr = match self.refers{
             Reference::Address(addr) => {addr},
@@ -73,9 +167,17 @@ impl ModbusFrame {
             },
         }
 
-        let tmp = Self::u16_to_u8_array(self.value);
-        buffer[len..len+2].copy_from_slice(&tmp);
-        len += 2;
+        if self.values.is_empty() {
+            let tmp = Self::u16_to_u8_array(self.value);
+            buffer[len..len+2].copy_from_slice(&tmp);
+            len += 2;
+        }
+
+        for value in &self.values {
+            let tmp = Self::u16_to_u8_array(*value);
+            buffer[len..len+2].copy_from_slice(&tmp);
+            len += 2;
+        }
 
         let crc = Self::calculate_crc16(&buffer[..len]);
         let mut crc = Self::u16_to_u8_array(crc);
@@ -88,9 +190,11 @@ impl ModbusFrame {
         len
     }
 
-    pub fn decode(buffer: &[u8]) -> Result<Self, &str> {
+    pub fn decode(buffer: &[u8]) -> Result<Self, &'static str> {
         rprintln!("decoded: {:?}", buffer);
 
+        if buffer.len() < 8 { return Err("short frame")};
+
         let crc = Self::calculate_crc16(buffer);
         if crc != 0 { return Err("bad crc")};
 
@@ -98,11 +202,163 @@ impl ModbusFrame {
             unit_id: buffer[0],
             command: buffer[1],
             refers: Reference::Address(Self::u8_array_to_u16(&buffer[2..4].try_into().unwrap())),
-            value: Self::u8_array_to_u16(&buffer[4..6].try_into().unwrap())
+            value: Self::u8_array_to_u16(&buffer[4..6].try_into().unwrap()),
+            values: Vec::new(),
         })
     }
 
-    fn calculate_crc16(data: &[u8]) -> u16{
+    /// Length of the RTU frame at the start of `adu`, found from the request or reply
+    /// layout of its function code and confirmed by the crc
+    pub fn rtu_frame_len(adu: &[u8]) -> Option<usize> {
+        if adu.len() < 4 { return None };
+        let count = |index: usize| adu.get(index).map(|count| *count as usize);
+
+        // either a request or a reply may be on the line
+        let lengths = match adu[1] {
+            function if function & 0x80 != 0 => { [Some(5), None] },
+            1..=4 => { [Some(8), count(2).map(|count| 5 + count)] },
+            5 | 6 => { [Some(8), None] },
+            15 | 16 => { [count(6).map(|count| 9 + count), Some(8)] },
+            22 => { [Some(10), None] },
+            23 => { [count(10).map(|count| 13 + count), count(2).map(|count| 5 + count)] },
+            _ => { [None, None] },
+        };
+
+        lengths.into_iter()
+        .flatten()
+        .find(|len| *len <= adu.len() && Self::calculate_crc16(&adu[..*len]) == 0)
+    }
+
+    /// Encode as a Modbus ASCII frame, returns the number of bytes used
+    pub fn encode_ascii(&self, buffer: &mut [u8]) -> usize {
+        let mut adu: [u8; MAX_ADU_LEN] = [0; MAX_ADU_LEN];
+        let len = self.encode(&mut adu);
+        Self::rtu_to_ascii(&adu[..len], buffer)
+    }
+
+    /// Re-frame an encoded RTU frame as ASCII, the crc is replaced by an LRC. A frame
+    /// failing its crc gets a failing LRC, so corrupted frames stay corrupted.
+    pub fn rtu_to_ascii(adu: &[u8], buffer: &mut [u8]) -> usize {
+        let crc_ok = Self::calculate_crc16(adu) == 0;
+        let adu = &adu[..adu.len().saturating_sub(2)];
+        let lrc = if crc_ok { Self::calculate_lrc(adu) } else { !Self::calculate_lrc(adu) };
+
+        buffer[0] = b':';
+        let mut pos = 1;
+        for byte in adu.iter().chain(core::iter::once(&lrc)) {
+            buffer[pos] = Self::hex_digit(byte >> 4);
+            buffer[pos + 1] = Self::hex_digit(byte & 0x0f);
+            pos += 2;
+        }
+        buffer[pos..pos + 2].copy_from_slice(b"\r\n");
+
+        pos + 2
+    }
+
+    /// Re-frame a Modbus ASCII frame, from its ':' through the CR LF, as an RTU frame
+    pub fn ascii_to_rtu(buffer: &[u8]) -> Result<Vec<u8, MAX_ADU_LEN>, &'static str> {
+        let hex = buffer
+            .strip_prefix(b":")
+            .and_then(|rest| rest.strip_suffix(b"\r\n"))
+            .ok_or("bad framing")?;
+        if hex.len() % 2 != 0 || hex.len() < 6 || hex.len() > (MAX_ADU_LEN - 1) * 2 {
+            return Err("bad length");
+        }
+
+        let mut adu: Vec<u8, MAX_ADU_LEN> = Vec::new();
+        for pair in hex.chunks(2) {
+            let high = Self::hex_value(pair[0]).ok_or("bad hex")?;
+            let low = Self::hex_value(pair[1]).ok_or("bad hex")?;
+            let _ = adu.push((high << 4) | low);
+        }
+
+        // the LRC makes the sum of all bytes zero
+        if Self::calculate_lrc(&adu) != 0 { return Err("bad lrc")};
+
+        // swap the LRC for a crc
+        adu.pop();
+        let crc = Self::calculate_crc16(&adu);
+        let _ = adu.extend_from_slice(&crc.to_le_bytes());
+        Ok(adu)
+    }
+
+    /// Bytes of a broken Modbus ASCII frame, the hex pairs after its ':' up to the first
+    /// character that is not hex, to record what was seen on the bus
+    pub fn ascii_bytes(buffer: &[u8]) -> Vec<u8, MAX_ADU_LEN> {
+        let hex = buffer.strip_prefix(b":").unwrap_or(buffer);
+
+        let mut adu: Vec<u8, MAX_ADU_LEN> = Vec::new();
+        for pair in hex.chunks_exact(2) {
+            match (Self::hex_value(pair[0]), Self::hex_value(pair[1])) {
+                (Some(high), Some(low)) if adu.push((high << 4) | low).is_ok() => {},
+                _ => { break },
+            }
+        }
+        adu
+    }
+
+    /// Encode as a Modbus TCP frame answering `transaction_id`, returns the number of bytes used
+    pub fn encode_mbap(&self, transaction_id: u16, buffer: &mut [u8]) -> usize {
+        let mut adu: [u8; MAX_ADU_LEN] = [0; MAX_ADU_LEN];
+        let len = self.encode(&mut adu) - 2;
+
+        let header = MbapHeader {
+            transaction_id,
+            protocol_id: 0,
+            length: len as u16,
+            unit_id: self.unit_id,
+        };
+        let pos = header.encode(buffer);
+        buffer[pos..pos + len - 1].copy_from_slice(&adu[1..len]);
+
+        pos + len - 1
+    }
+
+    /// Decode a complete Modbus TCP frame, the header is returned for the reply
+    pub fn decode_mbap(buffer: &[u8]) -> Result<(MbapHeader, Self), &'static str> {
+        let header = MbapHeader::decode(buffer).ok_or("short frame")?;
+        if header.protocol_id != 0 { return Err("not modbus")};
+        if header.length < 2 || header.frame_len() != buffer.len() { return Err("bad length")};
+
+        // the rest of the decoding is shared with RTU, so swap the header for unit id and crc
+        let mut adu: Vec<u8, MAX_ADU_LEN> = Vec::new();
+        let _ = adu.push(header.unit_id);
+        adu.extend_from_slice(&buffer[MBAP_HEADER_LEN..]).map_err(|_| "bad length")?;
+        let crc = Self::calculate_crc16(&adu);
+        adu.extend_from_slice(&crc.to_le_bytes()).map_err(|_| "bad length")?;
+
+        Ok((header, Self::decode(&adu)?))
+    }
+
+    /// Modbus TCP exception reply to the request with `header` and `function`
+    pub fn mbap_exception(header: &MbapHeader, function: u8, code: u8, buffer: &mut [u8]) -> usize {
+        let reply = MbapHeader { length: 3, ..*header };
+        let pos = reply.encode(buffer);
+        buffer[pos] = function | 0x80;
+        buffer[pos + 1] = code;
+
+        pos + 2
+    }
+
+    /// Two's complement of the 8 bit sum of the bytes
+    pub fn calculate_lrc(data: &[u8]) -> u8 {
+        data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)).wrapping_neg()
+    }
+
+    fn hex_digit(value: u8) -> u8 {
+        b"0123456789ABCDEF"[value as usize]
+    }
+
+    fn hex_value(digit: u8) -> Option<u8> {
+        match digit {
+            b'0'..=b'9' => { Some(digit - b'0') },
+            b'A'..=b'F' => { Some(digit - b'A' + 10) },
+            b'a'..=b'f' => { Some(digit - b'a' + 10) },
+            _ => { None },
+        }
+    }
+
+    pub fn calculate_crc16(data: &[u8]) -> u16{
 
         let mut crc: u16 = 0xFFFF;  // Initial CRC value
         let poly: u16 = 0xA001;  // CRC-16 polynomial
@@ -0,0 +1,158 @@
+use core::fmt::Write;
+use heapless::String;
+
+use crate::modbus::*;
+
+// text waiting to be streamed to the usb console
+const MONITOR_BUF_LEN: usize = 4096;
+
+// raw bytes shown per frame
+const RAW_MAX: usize = 64;
+
+/// Passive bus monitor, formats every frame seen on the line for the usb console
+pub struct BusMonitor {
+    enabled: bool,
+    unit_filter: Option<u8>,
+    function_filter: Option<u8>,
+    out: String<MONITOR_BUF_LEN>,
+    dropped: u32,
+}
+
+impl BusMonitor {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            unit_filter: None,
+            function_filter: None,
+            out: String::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Start monitoring, frames are shown only when they match the filters given
+    pub fn start(&mut self, unit_filter: Option<u8>, function_filter: Option<u8>) {
+        self.enabled = true;
+        self.unit_filter = unit_filter;
+        self.function_filter = function_filter;
+        self.dropped = 0;
+    }
+
+    pub fn stop(&mut self) {
+        self.enabled = false;
+        self.out.clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn unit_filter(&self) -> Option<u8> {
+        self.unit_filter
+    }
+
+    pub fn function_filter(&self) -> Option<u8> {
+        self.function_filter
+    }
+
+    /// Frames lost because the console did not keep up
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Record a frame received at `time_us` of uptime, `clean` is false after framing or overrun errors
+    pub fn record(&mut self, time_us: u64, adu: &[u8], clean: bool) {
+        if !self.enabled || adu.is_empty() {
+            return;
+        }
+
+        let unit_id = adu[0];
+        let function = adu.get(1).copied().unwrap_or(0);
+        if self.unit_filter.is_some_and(|id| id != unit_id)
+            || self.function_filter.is_some_and(|fc| fc != function & 0x7f) {
+            return;
+        }
+
+        let mut line: String<1536> = String::new();
+        let crc_ok = adu.len() >= 4 && ModbusFrame::calculate_crc16(adu) == 0;
+        let _ = write!(line, "{}.{:06} unit[{}] fc[{}] len[{}] {}",
+            time_us / 1_000_000,
+            time_us % 1_000_000,
+            unit_id,
+            function,
+            adu.len(),
+            if !clean {"line error"} else if crc_ok {"crc ok"} else {"bad crc"}, );
+
+        if clean && crc_ok {
+            Self::describe(&mut line, function, &adu[2..adu.len() - 2]);
+        }
+
+        let _ = write!(line, "\r\n ");
+        for byte in adu.iter().take(RAW_MAX) {
+            let _ = write!(line, " {:02x}", byte);
+        }
+        if adu.len() > RAW_MAX {
+            let _ = write!(line, " ..");
+        }
+        let _ = write!(line, "\r\n");
+
+        if self.out.push_str(&line).is_err() {
+            self.dropped += 1;
+        }
+    }
+
+    /// Take the text formatted since the last call
+    pub fn take_output(&mut self) -> Option<String<MONITOR_BUF_LEN>> {
+        if self.out.is_empty() {
+            return None;
+        }
+        Some(core::mem::take(&mut self.out))
+    }
+
+    /// Decode the pdu data following the function code, requests and replies are told apart by length
+    fn describe<const N: usize>(line: &mut String<N>, function: u8, data: &[u8]) {
+        let word = |i: usize| ((data[i] as u16) << 8) | data[i + 1] as u16;
+
+        if function & 0x80 != 0 {
+            let _ = write!(line, " exception[{}]", data.first().copied().unwrap_or(0));
+            return;
+        }
+
+        let _ = write!(line, " {}", match function {
+            1 => { "read coils" },
+            2 => { "read discrete inputs" },
+            3 => { "read holding registers" },
+            4 => { "read input registers" },
+            5 => { "write coil" },
+            6 => { "write register" },
+            15 => { "write coils" },
+            16 => { "write registers" },
+            _ => { "" },
+        });
+
+        match function {
+            1..=4 if data.len() == 4 => {
+                let _ = write!(line, " request addr[0x{:04x}] qty[{}]", word(0), word(2));
+            },
+            1..=4 if !data.is_empty() && data[0] as usize == data.len() - 1 => {
+                let _ = write!(line, " reply bytes[{}]", data[0]);
+                if function >= 3 {
+                    let _ = write!(line, " values[");
+                    for i in (1..data.len() - 1).step_by(2) {
+                        let _ = write!(line, "{}{}", if i > 1 {", "} else {""}, word(i));
+                    }
+                    let _ = write!(line, "]");
+                }
+            },
+            5 | 6 if data.len() == 4 => {
+                let _ = write!(line, " addr[0x{:04x}] value[{}]", word(0), word(2));
+            },
+            15 | 16 if data.len() == 4 => {
+                let _ = write!(line, " reply addr[0x{:04x}] qty[{}]", word(0), word(2));
+            },
+            15 | 16 if data.len() >= 5 => {
+                let _ = write!(line, " request addr[0x{:04x}] qty[{}] bytes[{}]", word(0), word(2), data[4]);
+            },
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,35 @@
+/// Small seedable xorshift32 pseudo random generator
+///
+/// the same seed always yields the same sequence, so runs driven by it can be reproduced
+pub struct Prng {
+    state: u32,
+}
+
+impl Prng {
+    pub fn new(seed: u32) -> Self {
+        // xorshift never leaves the all zero state, so substitute a fixed non zero seed
+        Self { state: if seed == 0 { 0x2545_f491 } else { seed } }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Random value from `min` through `max` inclusive
+    pub fn range(&mut self, min: u32, max: u32) -> u32 {
+        if max <= min {
+            return min;
+        }
+        min + self.next_u32() % (max - min + 1)
+    }
+
+    /// Returns true with a probability of `percent` / 100
+    pub fn chance(&mut self, percent: u8) -> bool {
+        self.next_u32() % 100 < percent as u32
+    }
+}
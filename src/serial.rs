@@ -1,48 +1,205 @@
 use stm32f4xx_hal as hal;
 
 use crate::hal::rcc::*;
-use crate::hal::pac::TIM2;
+use crate::hal::pac::{TIM5, GPIOA};
 use crate::hal::prelude::*;
 use crate::hal::timer::Counter;
+use crate::hal::pac::interrupt;
 
 use crate::hal::gpio::{Pin, Output};
 use crate::hal::uart::{Rx, Tx};
-use crate::hal::serial::{config::Config, Serial};
-use crate::hal::dma::{Transfer, StreamsTuple, StreamX, PeripheralToMemory};
+use crate::hal::serial::{config::Config, config::StopBits, Serial};
+use crate::hal::dma::{Transfer, StreamsTuple, StreamX, PeripheralToMemory, MemoryToPeripheral};
 use crate::hal::dma::config::DmaConfig;
 use crate::hal::pac::{USART2, DMA1};
 use crate::hal::time::Bps;
 
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::{free, Mutex};
+use heapless::{Deque, Vec};
 use rtt_target::rprintln;
 
 use crate::modbus::*;
 use crate::ev_charger::*;
+use crate::settings::{Parity, SerialSettings, Transport};
+use crate::fault::*;
+use crate::prng::Prng;
+use crate::monitor::BusMonitor;
+use crate::capture::{Capture, Direction};
 
 
-// Create buffers for sending and receiving data
-const BUF_LEN: usize = 20;
-static mut RX_BUFFER: [u8; BUF_LEN] = [0; BUF_LEN];
+// Create buffers for sending and receiving data, reception alternates between the
+// two rx buffers so a frame can be decoded while the next one arrives. They are
+// sized for the longer ASCII framing.
+const BUF_LEN: usize = MAX_ASCII_LEN;
+static mut RX_BUFFER_A: [u8; BUF_LEN] = [0; BUF_LEN];
+static mut RX_BUFFER_B: [u8; BUF_LEN] = [0; BUF_LEN];
+static mut TX_BUFFER: [u8; BUF_LEN] = [0; BUF_LEN];
 
+// longest pause allowed between the characters of an ASCII frame
+const ASCII_CHAR_TIMEOUT_US: u32 = 1_000_000;
 
-pub struct ModbusTransceiver<'a> {
-    sys_timer: &'a Counter<TIM2, 1000>,
+// how long a slave gets to answer a request sent as master, and how often it is repeated
+const DEFAULT_MASTER_TIMEOUT_US: u32 = 500_000;
+const DEFAULT_MASTER_RETRIES: u8 = 2;
+
+// allowance for starting the DMA before a transmission that was not read back is reported
+const ECHO_SLACK_US: u32 = 5_000;
+
+// replies waiting for their unit's response delay
+const PENDING_LEN: usize = 4;
+
+// Set while a reply is on the line, cleared by the transmission complete interrupt
+static TX_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Idle line detections recorded by the USART2 interrupt
+static IDLE_EVENTS: Mutex<RefCell<Deque<IdleEvent, 8>>> = Mutex::new(RefCell::new(Deque::new()));
+
+/// The receive line went idle for one character time
+#[derive(Clone, Copy)]
+struct IdleEvent {
+    /// us_timer ticks at detection
+    time: u32,
+    /// DMA transfers still outstanding at detection
+    remaining: u16,
+    /// the USART lost a character since the previous detection
+    overrun: bool,
+}
+
+/// Encoded reply waiting for its transmit time
+struct PendingReply {
+    adu: Vec<u8, MAX_ADU_LEN>,
+    /// us_timer ticks when it may be sent
+    due: u32,
+}
+
+/// Transmission waiting to be read back by the receiver
+struct Echo {
+    adu: Vec<u8, BUF_LEN>,
+    /// us_timer ticks by which it should have been heard
+    deadline: u32,
+    /// a reply as slave, rather than a request as master
+    reply: bool,
+}
+
+/// Shape of the last reply sent, a second one like it hints at a duplicate address
+struct Answered {
+    unit_id: u8,
+    function: u8,
+    len: usize,
+    /// us_timer ticks after which another unit would no longer be answering the same request
+    until: u32,
+}
+
+/// Why a request sent as bus master got no usable answer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MasterError {
+    Timeout,
+    Exception(u8),
+    BadReply,
+}
+
+/// Outcome of a request sent as bus master, read replies carry the register values
+pub struct MasterResponse {
+    pub request: ModbusFrame,
+    pub result: Result<Vec<u16, MAX_REGISTERS>, MasterError>,
+}
+
+/// Who asked for a request to be sent as bus master
+#[allow(clippy::large_enum_variant)]
+enum Origin {
+    /// a local function, which gets the decoded result
+    Master(ModbusFrame),
+    /// a frame passed through from the usb gateway, which gets the raw reply
+    Gateway,
+}
+
+/// Request sent as bus master waiting for its reply
+struct Transaction {
+    origin: Origin,
+    adu: Vec<u8, MAX_ADU_LEN>,
+    /// us_timer ticks when last sent
+    sent: u32,
+    attempts: u8,
+}
+
+/// Counters describing the health of the bus
+#[derive(Clone, Copy, Default, Debug)]
+pub struct BusStats {
+    pub frames: u32,
+    pub crc_errors: u32,
+    pub framing_errors: u32,
+    pub overruns: u32,
+    pub replies: u32,
+    pub master_requests: u32,
+    pub master_retries: u32,
+    pub master_timeouts: u32,
+    pub master_exceptions: u32,
+    /// transmissions read back intact
+    pub echoes: u32,
+    /// transmissions read back corrupted by another transmitter
+    pub collisions: u32,
+    /// transmissions never read back
+    pub missing_echoes: u32,
+    /// signs of another unit answering to one of our addresses
+    pub duplicate_addresses: u32,
+    /// frames that followed the previous one without the t3.5 gap
+    pub back_to_back: u32,
+}
+
+
+pub struct ModbusTransceiver {
+    us_timer: Counter<TIM5, 1_000_000>,
     rx_transfer: Transfer<StreamX<DMA1, 5>, 4, Rx<USART2>, PeripheralToMemory, &'static mut [u8; BUF_LEN]>,
-    last_xfs: u16,
-    last_rcv_to: Option<fugit::Instant<u32, 1, 1000>>,
+    rx_spare: Option<&'static mut [u8; BUF_LEN]>,
+    overrun: bool,
+    last_idle: Option<IdleEvent>,
+    framing_error: bool,
+    /// offsets in the rx buffer of characters that followed a pause over t1.5, and
+    /// whether the pause was also shorter than t3.5
+    pauses: Vec<(usize, bool), 8>,
+    tx_transfer: Transfer<StreamX<DMA1, 6>, 4, Tx<USART2>, MemoryToPeripheral, &'static mut [u8]>,
     den: Pin<'A', 4, Output>,
-    uart_tx: Tx<USART2>
-
+    char_us: u32,
+    t15_us: u32,
+    t35_us: u32,
+    transport: Transport,
+    stats: BusStats,
+    faults: FaultInjector,
+    /// replies waiting for their transmit time, earliest first
+    pending: Vec<PendingReply, PENDING_LEN>,
+    rng: Prng,
+    monitor: BusMonitor,
+    capture: Capture,
+    master_queue: Deque<(Origin, Vec<u8, MAX_ADU_LEN>), 8>,
+    transaction: Option<Transaction>,
+    responses: Deque<MasterResponse, 8>,
+    gateway_replies: Deque<Vec<u8, MAX_ADU_LEN>, 2>,
+    master_timeout_us: u32,
+    master_retries: u8,
+    us_last: u32,
+    us_wraps: u32,
+    echo_check: bool,
+    echo: Option<Echo>,
+    /// USART2 kernel clock, for reprogramming the baud rate
+    pclk: u32,
+    /// line settings waiting for the transmitter to finish
+    line_request: Option<SerialSettings>,
+    answered: Option<Answered>,
 }
 
-impl <'a>ModbusTransceiver<'a> {
+impl ModbusTransceiver {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         pa2: Pin<'A', 2>,
         pa3: Pin<'A', 3>,
         pa4: Pin<'A', 4>,
         usart2: USART2,
         dma1: DMA1,
+        line: &SerialSettings,
         clocks: &Clocks,
-        sys_timer: &'a Counter<TIM2, 1000>,
+        us_timer: Counter<TIM5, 1_000_000>,
     ) -> Self {
 
         let tx = pa2.into_alternate();
@@ -51,12 +208,21 @@ impl <'a>ModbusTransceiver<'a> {
 
         den.set_low();
 
+        // the parity bit counts as a data bit, so 8 data bits with parity needs a 9 bit word
         let ser_config = Config::default()
-        .baudrate(Bps(19200))
-        .parity_none()
-        .wordlength_8()
+        .baudrate(Bps(line.baud))
+        .stopbits(if line.stop_bits == 2 {StopBits::STOP2} else {StopBits::STOP1})
         .dma(hal::serial::config::DmaConfig::TxRx);
 
+        // ASCII with parity uses 7 data bits, so the parity bit fits an 8 bit word
+        let ser_config = match (line.parity, line.data_bits()) {
+            (Parity::None, _) => { ser_config.parity_none().wordlength_8() },
+            (Parity::Even, 7) => { ser_config.parity_even().wordlength_8() },
+            (Parity::Odd, 7) => { ser_config.parity_odd().wordlength_8() },
+            (Parity::Even, _) => { ser_config.parity_even().wordlength_9() },
+            (Parity::Odd, _) => { ser_config.parity_odd().wordlength_9() },
+        };
+
         rprintln!("ser config: {:?}", ser_config);
 
         let usart2: Serial<USART2> = Serial::new(
@@ -68,12 +234,17 @@ impl <'a>ModbusTransceiver<'a> {
         .unwrap();
 
         // Split UART peripheral into transmitter and receiver
-        let (uart2_tx, uart2_rx) = usart2.split();
+        let (uart2_tx, mut uart2_rx) = usart2.split();
+
+        // frame boundaries are found from idle line interrupts
+        uart2_rx.listen_idle();
+        unsafe { cortex_m::peripheral::NVIC::unmask(hal::pac::Interrupt::USART2) };
 
 
         // Initialize DMA
         let dma_channels = StreamsTuple::new(dma1);
         let rx_channel = dma_channels.5;
+        let tx_channel = dma_channels.6;
 
 
         let dma_config = DmaConfig::default()
@@ -85,97 +256,857 @@ impl <'a>ModbusTransceiver<'a> {
         let mut rx_transfer = Transfer::init_peripheral_to_memory(
             rx_channel,
             uart2_rx,
-            unsafe { &mut RX_BUFFER },
+            unsafe { &mut RX_BUFFER_A },
             None,
             dma_config,
         );
 
         rx_transfer.start(|p: &mut hal::uart::Rx<USART2>| rprintln!("data: {:?}", p.is_rx_not_empty()));
 
-        // let tx_transfer = Transfer::init_memory_to_peripheral(
-        //     tx_channel,
-        //     uart2_tx,
-        //     unsafe { &mut TX_BUFFER },
-        //     None,
-        //     dma_config
-        // );
+        // frames are started by send_tx_adu, the driver is released from the USART2 interrupt
+        let tx_transfer = Transfer::init_memory_to_peripheral(
+            tx_channel,
+            uart2_tx,
+            unsafe { &mut TX_BUFFER[..] },
+            None,
+            DmaConfig::default().memory_increment(true),
+        );
+
 
+        let (char_us, t15_us, t35_us) = frame_timing(line);
+
+        let seed = us_timer.now().ticks();
 
         Self {
-            sys_timer,
+            us_timer,
             rx_transfer,
-            last_xfs: BUF_LEN as u16,
-            last_rcv_to: None,
+            rx_spare: Some(unsafe { &mut RX_BUFFER_B }),
+            overrun: false,
+            last_idle: None,
+            framing_error: false,
+            pauses: Vec::new(),
+            tx_transfer,
             den,
-            uart_tx: uart2_tx
+            char_us,
+            t15_us,
+            t35_us,
+            transport: line.transport,
+            stats: BusStats::default(),
+            faults: FaultInjector::new(seed),
+            pending: Vec::new(),
+            rng: Prng::new(seed.rotate_left(16)),
+            monitor: BusMonitor::new(),
+            capture: Capture::new(),
+            master_queue: Deque::new(),
+            transaction: None,
+            responses: Deque::new(),
+            gateway_replies: Deque::new(),
+            master_timeout_us: DEFAULT_MASTER_TIMEOUT_US,
+            master_retries: DEFAULT_MASTER_RETRIES,
+            us_last: seed,
+            us_wraps: 0,
+            echo_check: line.echo,
+            echo: None,
+            pclk: clocks.pclk1().raw(),
+            line_request: None,
+            answered: None,
         }
 
     }
 
-    pub fn scan_rx_msg<F>(&mut self, chargers: &mut [EVCharger; 4], on_receive: F)
+    pub fn scan_rx_msg<F>(&mut self, chargers: &mut [EVCharger; 4], mut on_receive: F)
     where
-        F: Fn(&ModbusFrame, &mut [EVCharger; 4]) -> Option<ModbusFrame>,
+        F: FnMut(&ModbusFrame, &mut [EVCharger; 4]) -> Option<ModbusFrame>,
     {
+        // keep the usec uptime extension current
+        self.uptime_us();
+        self.apply_line();
+        self.send_pending();
+        self.run_master();
+        self.expire_echo();
+        self.expire_answered();
+
+        if self.transport == Transport::Ascii {
+            self.scan_ascii(chargers, &mut on_receive);
+            return;
+        }
+
+        while let Some(event) = free(|cs| IDLE_EVENTS.borrow(cs).borrow_mut().pop_front()) {
+            self.on_idle(event);
+        }
+
+        let last_idle = match self.last_idle {
+            Some(idle) => { idle },
+            None => { return },
+        };
+
+        // the idle interrupt fires one character after the last stop bit, the frame is
+        // complete once t3.5 has passed with no further characters arriving
+        let silent_us = self.us_timer.now().ticks().wrapping_sub(last_idle.time) + self.char_us;
         let xfrs = self.rx_transfer.number_of_transfers();
-        if self.last_xfs != xfrs {
-            self.last_rcv_to = Some(self.sys_timer.now() + 3.millis());
-            self.last_xfs = xfrs;
-        }
-
-        match self.last_rcv_to {
-            Some(tout) if self.sys_timer.now() >= tout  => {
-                let rx_size = BUF_LEN - xfrs  as usize;
-                let msg = unsafe{&RX_BUFFER[0..rx_size]};
-
-                match ModbusFrame::decode(msg) {
-                    Ok(msg) => {
-                        match on_receive(&msg, chargers) {
-                            Some(reply) => {
-                                self.send_tx_msg(reply)
-                                .unwrap_or_else(|err| {rprintln!("Bad Sed: {}", err); });
+        if silent_us < self.t35_us || xfrs != last_idle.remaining {
+            return;
+        }
+
+        let rx_size = BUF_LEN - xfrs  as usize;
+        let framing_error = self.framing_error;
+
+        // a full buffer means the frame did not fit and the rest was lost
+        let overrun = self.overrun || xfrs == 0 || rx_size > MAX_ADU_LEN;
+
+        let frame_buf = self.swap_rx();
+        let msg = &frame_buf[0..rx_size];
+
+        // our own transmission read back is not bus traffic, a reply may follow it too closely
+        let echo_len = if overrun { 0 } else { self.check_echo(msg) };
+        let msg = &msg[echo_len..];
+
+        // the frame ended one character before the idle detection
+        let ended_us = self.uptime_us().saturating_sub(silent_us as u64);
+
+        // a pause inside the buffer is only an error when it splits a frame, not when it
+        // separates frames that arrived before they could be processed
+        let frames = self.split_frames(msg, echo_len);
+        let valid = frames.iter().all(|&(start, len, _)| ModbusFrame::calculate_crc16(&msg[start..start + len]) == 0);
+
+        if msg.is_empty() {
+            // only our own transmission
+        } else if overrun || (framing_error && !valid) {
+            self.monitor.record(ended_us, msg, false);
+            self.capture.record(ended_us, Direction::Rx, msg);
+
+            if overrun {
+                self.stats.overruns += 1;
+                rprintln!("overrun, discarded {} bytes", rx_size);
+            } else {
+                self.stats.framing_errors += 1;
+                rprintln!("framing error, discarded: {:?}", msg);
+            }
+        } else {
+            for (start, len, back_to_back) in frames {
+                if back_to_back {
+                    self.stats.back_to_back += 1;
+                    rprintln!("back to back frame at {} bytes", start);
+                }
+                self.on_frame(&msg[start..start + len], ended_us, chargers, &mut on_receive);
+            }
+        }
+
+        self.rx_spare = Some(frame_buf);
+
+    }
+
+    /// Split a received block into frames at the pauses inside it, then by the length each
+    /// function code implies, what cannot be split is left as one frame. Returns the start
+    /// and length of each frame, and whether it followed the previous one without a t3.5 gap.
+    fn split_frames(&self, msg: &[u8], skipped: usize) -> Vec<(usize, usize, bool), 64> {
+        let pauses = self.pauses.iter()
+        .filter(|(offset, _)| *offset > skipped && *offset - skipped < msg.len())
+        .map(|(offset, short)| (offset - skipped, *short))
+        .chain(core::iter::once((msg.len(), false)));
+
+        let mut frames = Vec::new();
+        let mut start = 0;
+        let mut short = false;
+        for (end, short_pause) in pauses {
+            while start < end {
+                let rest = &msg[start..end];
+                let len = if ModbusFrame::calculate_crc16(rest) == 0 {
+                    rest.len()
+                } else {
+                    ModbusFrame::rtu_frame_len(rest).unwrap_or(rest.len())
+                };
+
+                // at most 8 pauses and frames split by length are at least 5 bytes, so this can not fill up
+                let _ = frames.push((start, len, short));
+                start += len;
+                short = true;
+            }
+            short = short_pause;
+        }
+
+        frames
+    }
+
+    /// Process a complete RTU frame received without errors
+    fn on_frame<F>(&mut self, msg: &[u8], ended_us: u64, chargers: &mut [EVCharger; 4], on_receive: &mut F)
+    where
+        F: FnMut(&ModbusFrame, &mut [EVCharger; 4]) -> Option<ModbusFrame>,
+    {
+        self.monitor.record(ended_us, msg, true);
+        self.capture.record(ended_us, Direction::Rx, msg);
+
+        if self.match_reply(msg) || self.check_answered(msg) {
+            self.stats.frames += 1;
+            return;
+        }
+
+        match ModbusFrame::decode(msg) {
+            Ok(msg) => {
+                self.stats.frames += 1;
+                self.answer(&msg, chargers, on_receive);
+            }
+            _ => { self.stats.crc_errors += 1; }
+        }
+    }
+
+    /// Modbus ASCII: a frame runs from ':' through CR LF, with up to a second between characters
+    fn scan_ascii<F>(&mut self, chargers: &mut [EVCharger; 4], on_receive: &mut F)
+    where
+        F: FnMut(&ModbusFrame, &mut [EVCharger; 4]) -> Option<ModbusFrame>,
+    {
+        while let Some(event) = free(|cs| IDLE_EVENTS.borrow(cs).borrow_mut().pop_front()) {
+            self.overrun |= event.overrun;
+            self.last_idle = Some(event);
+        }
+
+        let last_idle = match self.last_idle {
+            Some(idle) => { idle },
+            None => { return },
+        };
+
+        // wait for the idle detection after the latest characters
+        if self.rx_transfer.number_of_transfers() != last_idle.remaining {
+            return;
+        }
+
+        let rx_size = BUF_LEN - last_idle.remaining as usize;
+        let ended = rx_size >= 2 && self.peek_rx(rx_size - 1) & 0x7f == b'\n';
+        let stale = self.us_timer.now().ticks().wrapping_sub(last_idle.time) > ASCII_CHAR_TIMEOUT_US;
+        let full = last_idle.remaining == 0;
+        if !(ended || stale || full) {
+            return;
+        }
+
+        let overrun = self.overrun || full;
+        let frame_buf = self.swap_rx();
+
+        // with 7 data bits the parity bit is read as the msb
+        for chr in frame_buf[..rx_size].iter_mut() {
+            *chr &= 0x7f;
+        }
+        let msg = &frame_buf[..rx_size];
+
+        let echo_len = if overrun { 0 } else { self.check_echo(msg) };
+        let msg = &msg[echo_len..];
+
+        // a ':' always starts a new frame, discarding anything before it
+        let text = match msg.iter().rposition(|chr| *chr == b':') {
+            Some(start) => { &msg[start..] },
+            None => { msg },
+        };
+
+        // the monitor and the capture see the RTU frame the text carries
+        let silent_us = self.us_timer.now().ticks().wrapping_sub(last_idle.time);
+        let ended_us = self.uptime_us().saturating_sub(silent_us as u64);
+
+        if msg.is_empty() {
+            // only our own transmission read back
+        } else if overrun || !ended {
+            let adu = ModbusFrame::ascii_bytes(text);
+            self.monitor.record(ended_us, &adu, false);
+            self.capture.record(ended_us, Direction::Rx, &adu);
+
+            if overrun {
+                self.stats.overruns += 1;
+                rprintln!("overrun, discarded {} bytes", rx_size);
+            } else {
+                self.stats.framing_errors += 1;
+                rprintln!("ascii timeout, discarded: {:?}", msg);
+            }
+        } else {
+            // decoded as RTU, so replies to requests sent as master are matched the same way
+            match ModbusFrame::ascii_to_rtu(text) {
+                Ok(adu) => {
+                    self.monitor.record(ended_us, &adu, true);
+                    self.capture.record(ended_us, Direction::Rx, &adu);
+
+                    if self.match_reply(&adu) || self.check_answered(&adu) {
+                        self.stats.frames += 1;
+                    } else {
+                        match ModbusFrame::decode(&adu) {
+                            Ok(msg) => {
+                                self.stats.frames += 1;
+                                self.answer(&msg, chargers, on_receive);
                             },
-                            _ => ()
+                            _ => { self.stats.crc_errors += 1; }
                         }
                     }
-                    _ => {}
+                },
+                _ => {
+                    let adu = ModbusFrame::ascii_bytes(text);
+                    self.monitor.record(ended_us, &adu, false);
+                    self.capture.record(ended_us, Direction::Rx, &adu);
+                    self.stats.crc_errors += 1;
+                },
+            }
+        }
+
+        self.rx_spare = Some(frame_buf);
+    }
+
+    /// Byte `index` of the rx buffer the DMA is currently filling
+    fn peek_rx(&self, index: usize) -> u8 {
+        let dma = unsafe { &*DMA1::ptr() };
+        let buffer = dma.st[5].m0ar.read().bits() as *const u8;
+        unsafe { core::ptr::read_volatile(buffer.add(index)) }
+    }
+
+    /// Hand a request to the local units and schedule their reply
+    fn answer<F>(&mut self, msg: &ModbusFrame, chargers: &mut [EVCharger; 4], on_receive: &mut F)
+    where
+        F: FnMut(&ModbusFrame, &mut [EVCharger; 4]) -> Option<ModbusFrame>,
+    {
+        // a monitoring unit only listens
+        let reply = if self.monitor.is_enabled() { None } else { on_receive(msg, chargers) };
+        if let Some(reply) = reply {
+            // emulate the unit's processing time, the site aggregate answers at once
+            let delay = chargers.iter()
+            .find(|chrg| chrg.get_id() == reply.unit_id)
+            .map(|chrg| chrg.response_delay())
+            .unwrap_or_default();
+            let delay_ms = self.rng.range(delay.min_ms as u32, delay.max_ms as u32);
+            self.queue_reply(reply, delay_ms * 1000);
+        }
+    }
+
+    /// Track the gaps within a frame from consecutive idle line detections
+    fn on_idle(&mut self, event: IdleEvent) {
+        self.overrun |= event.overrun;
+
+        if let Some(prev) = self.last_idle {
+            // the characters received since the previous idle arrived back to back,
+            // so the silence before them is what remains of the time between detections
+            let chars = prev.remaining.saturating_sub(event.remaining) as u32;
+            let elapsed = event.time.wrapping_sub(prev.time);
+            let gap_us = elapsed.saturating_sub(chars * self.char_us);
+            if chars > 0 && gap_us > self.t15_us {
+                self.framing_error = true;
+                let offset = BUF_LEN - prev.remaining as usize;
+                let _ = self.pauses.push((offset, gap_us < self.t35_us));
+            }
+        }
+
+        self.last_idle = Some(event);
+    }
+
+    /// Compare a received frame with the transmission being read back, returns the length
+    /// of the transmission when the frame starts with it. Anything else heard while we
+    /// drove the line means another transmitter was active too.
+    fn check_echo(&mut self, msg: &[u8]) -> usize {
+        let echo = match self.echo.take() {
+            Some(echo) => { echo },
+            None => { return 0 },
+        };
+
+        if msg.starts_with(&echo.adu) {
+            self.stats.echoes += 1;
+            return echo.adu.len();
+        }
+
+        self.stats.collisions += 1;
+        rprintln!("collision, sent: {:?} heard: {:?}", &echo.adu[..], msg);
+
+        // only a unit with the same address answers the request we answered
+        if echo.reply {
+            self.stats.duplicate_addresses += 1;
+            rprintln!("collision on a reply from unit {}, duplicate address?", echo.adu[0]);
+        }
+        0
+    }
+
+    /// Count a transmission that was never read back once its deadline has passed on a quiet line
+    fn expire_echo(&mut self) {
+        let deadline = match &self.echo {
+            Some(echo) => { echo.deadline },
+            None => { return },
+        };
+
+        let now = self.us_timer.now().ticks();
+        if (now.wrapping_sub(deadline) as i32) < 0 || self.line_busy() {
+            return;
+        }
+
+        self.echo = None;
+        self.stats.missing_echoes += 1;
+        rprintln!("echo: transmission not heard");
+    }
+
+    /// Check whether the frame after a reply is a second reply of the same shape, which
+    /// only a unit with the same address would send. Write replies repeat the request, so
+    /// they can not be told apart from a master retrying.
+    fn check_answered(&mut self, adu: &[u8]) -> bool {
+        let duplicate = match self.answered.take() {
+            Some(answered) => {
+                adu.len() == answered.len
+                && adu[0] == answered.unit_id
+                && adu[1] == answered.function
+                && !matches!(answered.function, 5 | 6)
+                && ModbusFrame::calculate_crc16(adu) == 0
+            },
+            None => { false },
+        };
+
+        if duplicate {
+            self.stats.duplicate_addresses += 1;
+            rprintln!("second reply from unit {}, duplicate address?", adu[0]);
+        }
+        duplicate
+    }
+
+    /// Forget the last reply once a second unit answering the same request would have been heard
+    fn expire_answered(&mut self) {
+        let until = match &self.answered {
+            Some(answered) => { answered.until },
+            None => { return },
+        };
+
+        let now = self.us_timer.now().ticks();
+        if (now.wrapping_sub(until) as i32) >= 0 && !self.line_busy() {
+            self.answered = None;
+        }
+    }
+
+    pub fn echo_check(&self) -> bool {
+        self.echo_check
+    }
+
+    /// Read back transmissions, only for transceivers that keep the receiver enabled
+    pub fn set_echo_check(&mut self, enabled: bool) {
+        self.echo_check = enabled;
+        self.echo = None;
+    }
+
+    /// Point the DMA at the spare buffer and return the one holding the received frame
+    fn swap_rx(&mut self) -> &'static mut [u8; BUF_LEN] {
+        self.last_idle = None;
+        self.framing_error = false;
+        self.pauses.clear();
+        self.overrun = false;
+
+        let spare = self.rx_spare.take().unwrap();
+        let (frame_buf, _) = self.rx_transfer.next_transfer(spare)
+        .unwrap();
+
+        // detections from before the swap refer to the old buffer
+        free(|cs| IDLE_EVENTS.borrow(cs).borrow_mut().clear());
+
+        frame_buf
+    }
+
+    /// Change the line settings, applied in place once the transmitter is idle
+    pub fn set_line(&mut self, line: &SerialSettings) {
+        self.line_request = Some(*line);
+    }
+
+    /// Reprogram USART2 for the requested line settings, anything half received or waiting
+    /// to be sent in the old framing is dropped
+    fn apply_line(&mut self) {
+        if self.line_request.is_none() || self.is_tx_busy() {
+            return;
+        }
+        let line = self.line_request.take().unwrap();
+
+        // the parity bit counts as a data bit, ASCII with parity fits an 8 bit word
+        let parity = line.parity != Parity::None;
+        let nine_bits = parity && line.data_bits() == 8;
+
+        // the DMA requests stay enabled while the USART is briefly disabled
+        let usart = unsafe { &*USART2::ptr() };
+        usart.cr1.modify(|_, w| w.ue().clear_bit());
+        usart.brr.write(|w| unsafe { w.bits((self.pclk + line.baud / 2) / line.baud) });
+        usart.cr1.modify(|_, w| w
+            .m().bit(nine_bits)
+            .pce().bit(parity)
+            .ps().bit(line.parity == Parity::Odd));
+        usart.cr2.modify(|_, w| if line.stop_bits == 2 { w.stop().stop2() } else { w.stop().stop1() });
+        usart.cr1.modify(|_, w| w.ue().set_bit());
+
+        (self.char_us, self.t15_us, self.t35_us) = frame_timing(&line);
+        self.transport = line.transport;
+        self.pending.clear();
+        self.echo = None;
+
+        // restart reception in the new framing
+        let frame_buf = self.swap_rx();
+        self.rx_spare = Some(frame_buf);
+
+        rprintln!("line: {:?}", line);
+    }
+
+    pub fn stats(&self) -> BusStats {
+        self.stats
+    }
+
+    /// Queue a request to send as bus master, the outcome is collected with `take_response`
+    pub fn queue_request(&mut self, request: ModbusFrame) -> Result<(), &'static str> {
+        rprintln!("<-- master: {:?}", request);
+
+        let mut tx_data: [u8; MAX_ADU_LEN] = [0; MAX_ADU_LEN];
+        let len = request.encode(&mut tx_data);
+        let adu: Vec<u8, MAX_ADU_LEN> = Vec::from_slice(&tx_data[..len]).unwrap();
+        self.master_queue.push_back((Origin::Master(request), adu)).map_err(|_| "master queue full")
+    }
+
+    pub fn take_response(&mut self) -> Option<MasterResponse> {
+        self.responses.pop_front()
+    }
+
+    /// Queue a complete frame from the usb gateway, the raw reply is collected with `take_gateway_reply`
+    pub fn forward(&mut self, adu: &[u8]) -> Result<(), &'static str> {
+        let adu: Vec<u8, MAX_ADU_LEN> = Vec::from_slice(adu).map_err(|_| "frame too long")?;
+        self.master_queue.push_back((Origin::Gateway, adu)).map_err(|_| "master queue full")
+    }
+
+    pub fn take_gateway_reply(&mut self) -> Option<Vec<u8, MAX_ADU_LEN>> {
+        self.gateway_replies.pop_front()
+    }
+
+    /// No master request is queued or waiting for a reply
+    pub fn master_idle(&self) -> bool {
+        self.master_queue.is_empty() && self.transaction.is_none()
+    }
+
+    pub fn master_timeout_ms(&self) -> u32 {
+        self.master_timeout_us / 1000
+    }
+
+    pub fn master_retries(&self) -> u8 {
+        self.master_retries
+    }
+
+    pub fn set_master(&mut self, timeout_ms: u32, retries: u8) {
+        self.master_timeout_us = timeout_ms * 1000;
+        self.master_retries = retries;
+    }
+
+    /// Time out or repeat the outstanding request, and start the next one once the line is free
+    fn run_master(&mut self) {
+        let now = self.us_timer.now().ticks();
+
+        if let Some(transaction) = &self.transaction {
+            if now.wrapping_sub(transaction.sent) < self.master_timeout_us {
+                return;
+            }
+
+            // the gateway host repeats requests itself
+            let retries = match transaction.origin {
+                Origin::Master(_) => { self.master_retries },
+                Origin::Gateway => { 0 },
+            };
+
+            if transaction.attempts > retries {
+                let transaction = self.transaction.take().unwrap();
+                self.stats.master_timeouts += 1;
+                if let Origin::Master(request) = transaction.origin {
+                    self.finish_transaction(request, Err(MasterError::Timeout));
+                }
+            } else if !self.line_busy() && self.pending.is_empty() {
+                let adu = transaction.adu.clone();
+                self.stats.master_retries += 1;
+                self.send_transaction(adu);
+            }
+            return;
+        }
+
+        if self.line_busy() || !self.pending.is_empty() {
+            return;
+        }
+
+        if let Some((origin, adu)) = self.master_queue.pop_front() {
+            self.stats.master_requests += 1;
+
+            // nobody answers a broadcast
+            if adu[0] == 0 {
+                self.send_frame(&adu)
+                .unwrap_or_else(|err| {rprintln!("Bad Sed: {}", err); });
+                if let Origin::Master(request) = origin {
+                    self.finish_transaction(request, Ok(Vec::new()));
                 }
+                return;
+            }
 
-                self.last_rcv_to = None;
-                self.last_xfs = BUF_LEN as u16;
-                unsafe{RX_BUFFER = [0; BUF_LEN]};
-                self.rx_transfer.next_transfer(unsafe{&mut RX_BUFFER})
-                .unwrap();
+            self.transaction = Some(Transaction { origin, adu: adu.clone(), sent: now, attempts: 0 });
+            self.send_transaction(adu);
+        }
+    }
+
+    fn send_transaction(&mut self, adu: Vec<u8, MAX_ADU_LEN>) {
+        let now = self.us_timer.now().ticks();
+        if let Some(transaction) = &mut self.transaction {
+            transaction.sent = now;
+            transaction.attempts += 1;
+        }
+        self.send_frame(&adu)
+        .unwrap_or_else(|err| {rprintln!("Bad Sed: {}", err); });
+    }
+
+    /// Check whether a received frame answers the outstanding master request
+    fn match_reply(&mut self, adu: &[u8]) -> bool {
+        let matches = match &self.transaction {
+            Some(transaction) => {
+                adu.len() >= 5
+                && ModbusFrame::calculate_crc16(adu) == 0
+                && adu[0] == transaction.adu[0]
+                && adu[1] & 0x7f == transaction.adu[1] & 0x7f
             },
-            _ => {}
+            None => { false },
+        };
+        if !matches {
+            return false;
         }
 
+        let transaction = self.transaction.take().unwrap();
+        let request = match transaction.origin {
+            Origin::Master(request) => { request },
+            Origin::Gateway => {
+                if self.gateway_replies.push_back(Vec::from_slice(adu).unwrap()).is_err() {
+                    rprintln!("gateway: reply dropped");
+                }
+                return true;
+            },
+        };
+        let data = &adu[2..adu.len() - 2];
+
+        let result = match request.command {
+            _ if adu[1] & 0x80 != 0 => {
+                self.stats.master_exceptions += 1;
+                Err(MasterError::Exception(data[0]))
+            },
+            3 | 4 if data[0] as usize == data.len() - 1 && data[0] as u16 == request.value * 2 => {
+                let mut values: Vec<u16, MAX_REGISTERS> = Vec::new();
+                for pair in data[1..].chunks(2) {
+                    let _ = values.push(((pair[0] as u16) << 8) | pair[1] as u16);
+                }
+                Ok(values)
+            },
+            5 | 6 | 15 | 16 if data.len() == 4 => { Ok(Vec::new()) },
+            _ => { Err(MasterError::BadReply) },
+        };
+
+        self.finish_transaction(request, result);
+        true
+    }
+
+    fn finish_transaction(&mut self, request: ModbusFrame, result: Result<Vec<u16, MAX_REGISTERS>, MasterError>) {
+        rprintln!("--> master: {:?} {:?}", request, result);
+        if self.responses.push_back(MasterResponse { request, result }).is_err() {
+            rprintln!("master: response dropped");
+        }
     }
 
-    pub fn send_tx_msg(&mut self, msg: ModbusFrame) -> Result<(), &str> {
+    /// Usec since start up, must be called at least once per us_timer wrap (~71 min)
+    pub fn uptime_us(&mut self) -> u64 {
+        let ticks = self.us_timer.now().ticks();
+        if ticks < self.us_last {
+            self.us_wraps += 1;
+        }
+        self.us_last = ticks;
 
-        rprintln!("<-- send: {:?}", msg);
+        ((self.us_wraps as u64) << 32) | ticks as u64
+    }
 
-        self.den.set_high();
+    pub fn monitor(&self) -> &BusMonitor {
+        &self.monitor
+    }
+
+    pub fn monitor_mut(&mut self) -> &mut BusMonitor {
+        &mut self.monitor
+    }
+
+    pub fn capture(&self) -> &Capture {
+        &self.capture
+    }
+
+    pub fn capture_mut(&mut self) -> &mut Capture {
+        &mut self.capture
+    }
 
-        let mut tx_data: [u8; BUF_LEN] = [0; BUF_LEN];
+    pub fn faults(&self) -> &FaultInjector {
+        &self.faults
+    }
+
+    pub fn faults_mut(&mut self) -> &mut FaultInjector {
+        &mut self.faults
+    }
+
+    /// Encode a reply, pass it through fault injection and schedule it `delay_us` from now
+    fn queue_reply(&mut self, msg: ModbusFrame, delay_us: u32) {
+        rprintln!("<-- reply: {:?}", msg);
+
+        let mut tx_data: [u8; MAX_ADU_LEN] = [0; MAX_ADU_LEN];
         let len = msg.encode(&mut tx_data);
+        let mut adu: Vec<u8, MAX_ADU_LEN> = Vec::from_slice(&tx_data[..len]).unwrap();
 
-        let tx_data = &tx_data[..len];
+        match self.faults.inject(&mut adu) {
+            Injected::Dropped => { rprintln!("fault: reply dropped"); },
+            Injected::Send(extra_us) => {
+                let due = self.us_timer.now().ticks().wrapping_add(delay_us + extra_us);
+                let index = self.pending.iter()
+                .position(|queued| (due.wrapping_sub(queued.due) as i32) < 0)
+                .unwrap_or(self.pending.len());
+                if self.pending.insert(index, PendingReply { adu, due }).is_err() {
+                    rprintln!("reply queue full, reply dropped");
+                }
+            },
+        }
 
-        for d in tx_data {
-            while !self.uart_tx.is_tx_empty(){};
+        self.send_pending();
+    }
 
-            stm32f4xx_hal::prelude::_embedded_hal_serial_nb_Write::write(&mut self.uart_tx, *d)
-            .unwrap();
+    /// Start transmitting the earliest pending reply once it is due and the line is free
+    fn send_pending(&mut self) {
+        let due = match self.pending.first() {
+            Some(pending) => { pending.due },
+            None => { return },
+        };
 
+        let now = self.us_timer.now().ticks();
+        if (now.wrapping_sub(due) as i32) < 0 || self.line_busy() {
+            return;
         }
 
-        let timeout: fugit::Instant<u32, 1, 1000> = self.sys_timer.now() + 2.millis();
-        while self.sys_timer.now() < timeout { }
+        let pending = self.pending.remove(0);
+        let sent = self.send_frame(&pending.adu)
+        .map_err(|err| {rprintln!("Bad Sed: {}", err); })
+        .is_ok();
+
+        if sent {
+            self.stats.replies += 1;
+            if let Some(echo) = &mut self.echo {
+                echo.reply = true;
+            }
+            // a second unit answers within the time a master waits for the reply, once ours has ended
+            let chars = match self.transport {
+                Transport::Rtu => { pending.adu.len() as u32 },
+                Transport::Ascii => { pending.adu.len() as u32 * 2 + 1 },
+            };
+            let until = self.us_timer.now().ticks()
+            .wrapping_add(chars * self.char_us + self.t35_us + self.master_timeout_us);
+            self.answered = Some(Answered {
+                unit_id: pending.adu[0],
+                function: pending.adu[1],
+                len: pending.adu.len(),
+                until,
+            });
+        }
+    }
+
+    /// A reply is still being transmitted
+    pub fn is_tx_busy(&self) -> bool {
+        TX_ACTIVE.load(Ordering::Acquire)
+    }
+
+    /// Transmitting, or receiving a frame that has not been processed yet
+    fn line_busy(&self) -> bool {
+        self.is_tx_busy() || self.last_idle.is_some()
+    }
+
+    /// Start transmitting an encoded RTU frame in the configured framing, the capture keeps
+    /// the RTU frame
+    fn send_frame(&mut self, adu: &[u8]) -> Result<(), &str> {
+        let result = match self.transport {
+            Transport::Rtu => { self.send_tx_adu(adu) },
+            Transport::Ascii => {
+                let mut tx_data: [u8; BUF_LEN] = [0; BUF_LEN];
+                let len = ModbusFrame::rtu_to_ascii(adu, &mut tx_data);
+                self.send_tx_adu(&tx_data[..len])
+            },
+        };
+
+        if result.is_ok() {
+            let now_us = self.uptime_us();
+            self.capture.record(now_us, Direction::Tx, adu);
+        }
+        result
+    }
+
+    /// Start transmitting an already encoded frame
+    fn send_tx_adu(&mut self, adu: &[u8]) -> Result<(), &'static str> {
+
+        if self.is_tx_busy() {
+            return Err("transmitter busy");
+        }
+
+        let len = adu.len();
+        unsafe{TX_BUFFER[..len].copy_from_slice(adu)};
+
+        TX_ACTIVE.store(true, Ordering::Release);
+        self.den.set_high();
+
+        // TC must be cleared before the first byte so it only rises after the last stop bit
+        let usart = unsafe { &*USART2::ptr() };
+        usart.sr.modify(|_, w| w.tc().clear_bit());
+
+        if self.tx_transfer.next_transfer(unsafe{&mut TX_BUFFER[..len]}).is_err() {
+            TX_ACTIVE.store(false, Ordering::Release);
+            self.den.set_low();
+            return Err("dma not ready");
+        }
+        usart.cr1.modify(|_, w| w.tcie().set_bit());
+
+        // read back once the whole frame and t3.5 have passed
+        if self.echo_check {
+            let deadline = self.us_timer.now().ticks()
+            .wrapping_add(len as u32 * self.char_us + self.t35_us + ECHO_SLACK_US);
+            self.echo = Some(Echo { adu: Vec::from_slice(adu).unwrap(), deadline, reply: false });
+        }
 
-        self.den.set_low();
         Ok(())
 
     }
 
 }
+
+/// Character time, and the t1.5 / t3.5 gaps in us: a frame ends after 3.5 character times of
+/// silence and may not contain gaps over 1.5, above 19200 baud the spec fixes them at 1750 / 750 us
+fn frame_timing(line: &SerialSettings) -> (u32, u32, u32) {
+    let char_bits = line.bits_per_char();
+    let char_us = (char_bits * 1_000_000).div_ceil(line.baud);
+    let (t15_us, t35_us) = if line.baud > 19200 {
+        (750, 1750)
+    } else {
+        (char_us * 3 / 2, char_us * 7 / 2)
+    };
+
+    rprintln!("char: {} us, t1.5: {} us, t3.5: {} us", char_us, t15_us, t35_us);
+    (char_us, t15_us, t35_us)
+}
+
+#[interrupt]
+fn USART2() {
+    let usart = unsafe { &*USART2::ptr() };
+    let sr = usart.sr.read();
+
+    // last stop bit of a reply has left the line, release the RS-485 driver (PA4)
+    if sr.tc().bit_is_set() && usart.cr1.read().tcie().bit_is_set() {
+        usart.cr1.modify(|_, w| w.tcie().clear_bit());
+        usart.sr.modify(|_, w| w.tc().clear_bit());
+        unsafe { (*GPIOA::ptr()).bsrr.write(|w| w.br4().set_bit()) };
+        TX_ACTIVE.store(false, Ordering::Release);
+    }
+
+    // reading SR then DR clears the idle and overrun flags, the data itself was already
+    // taken by the DMA
+    if sr.idle().bit_is_set() {
+        let _ = usart.dr.read();
+
+        let mut event = IdleEvent {
+            time: unsafe { (*TIM5::ptr()).cnt.read().bits() },
+            remaining: unsafe { (*DMA1::ptr()).st[5].ndtr.read().ndt().bits() },
+            overrun: sr.ore().bit_is_set(),
+        };
+
+        // when the main loop falls behind drop the oldest event, the newest carries the
+        // transfer count the received frame is matched against
+        free(|cs| {
+            let mut events = IDLE_EVENTS.borrow(cs).borrow_mut();
+            if events.is_full() {
+                if let Some(oldest) = events.pop_front() {
+                    event.overrun |= oldest.overrun;
+                }
+            }
+            let _ = events.push_back(event);
+        });
+    }
+}
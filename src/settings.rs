@@ -0,0 +1,296 @@
+use crate::ev_charger::*;
+
+pub const SETTINGS_RECORD_LEN: usize = 64;
+
+const SETTINGS_VERSION: u8 = 1;
+
+// byte offsets within the settings payload, unused bytes are left erased (0xff)
+const VERSION_OFFSET: usize = 0;
+const POLICY_OFFSET: usize = 1;
+const SITE_OFFSET: usize = 5;
+const BAUD_OFFSET: usize = 6;
+const PARITY_OFFSET: usize = 10;
+const STOP_BITS_OFFSET: usize = 11;
+const DELAY_OFFSET: usize = 12;
+const TRANSPORT_OFFSET: usize = 28;
+const ECHO_OFFSET: usize = 29;
+const PIN_OFFSET: usize = 30;
+
+/// Time a unit takes to answer a request, a random value from min through max
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResponseDelay {
+    pub min_ms: u16,
+    pub max_ms: u16,
+}
+
+impl ResponseDelay {
+    pub const MAX_MS: u16 = 5000;
+
+    pub fn is_valid(&self) -> bool {
+        self.min_ms <= self.max_ms && self.max_ms <= Self::MAX_MS
+    }
+}
+
+/// Digits guarding the service commands, nothing is accepted until one is configured
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ServicePin {
+    digits: [u8; ServicePin::MAX_LEN],
+    len: usize,
+}
+
+impl ServicePin {
+    pub const MIN_LEN: usize = 4;
+    pub const MAX_LEN: usize = 8;
+
+    pub fn parse(pin: &str) -> Option<Self> {
+        let bytes = pin.as_bytes();
+        if !(Self::MIN_LEN..=Self::MAX_LEN).contains(&bytes.len()) || !bytes.iter().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut digits = [0xff; Self::MAX_LEN];
+        digits[..bytes.len()].copy_from_slice(bytes);
+        Some(Self { digits, len: bytes.len() })
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.len > 0
+    }
+
+    pub fn matches(&self, pin: &str) -> bool {
+        self.is_set() && &self.digits[..self.len] == pin.as_bytes()
+    }
+}
+
+/// Configuration of one unit of the bank, indexed by ui bank
+#[derive(Clone, Copy, Debug)]
+pub struct UnitSettings {
+    pub start_policy: StartPolicy,
+    pub response_delay: ResponseDelay,
+}
+
+impl Default for UnitSettings {
+    fn default() -> Self {
+        Self {
+            start_policy: StartPolicy::MasterEnable,
+            response_delay: ResponseDelay::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl Parity {
+    pub fn from(value: u8) -> Self {
+        match value {
+            0x01 => { Self::Even },
+            0x02 => { Self::Odd },
+            _ => { Self::None },
+        }
+    }
+
+    pub fn to(&self) -> u8 {
+        match self {
+            Self::None => { 0x00 },
+            Self::Even => { 0x01 },
+            Self::Odd => { 0x02 },
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "N" | "n" => { Some(Self::None) },
+            "E" | "e" => { Some(Self::Even) },
+            "O" | "o" => { Some(Self::Odd) },
+            _ => { None },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => { "N" },
+            Self::Even => { "E" },
+            Self::Odd => { "O" },
+        }
+    }
+}
+
+/// Framing of modbus messages on the line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Rtu,
+    Ascii,
+}
+
+impl Transport {
+    pub fn from(value: u8) -> Self {
+        match value {
+            0x01 => { Self::Ascii },
+            _ => { Self::Rtu },
+        }
+    }
+
+    pub fn to(&self) -> u8 {
+        match self {
+            Self::Rtu => { 0x00 },
+            Self::Ascii => { 0x01 },
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "rtu" => { Some(Self::Rtu) },
+            "ascii" => { Some(Self::Ascii) },
+            _ => { None },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rtu => { "rtu" },
+            Self::Ascii => { "ascii" },
+        }
+    }
+}
+
+/// RS-485 line parameters of the modbus interface, 8 data bits except for ASCII
+/// with parity, which uses the usual 7
+#[derive(Clone, Copy, Debug)]
+pub struct SerialSettings {
+    pub baud: u32,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub transport: Transport,
+    /// the receiver stays enabled while transmitting, so replies are read back
+    pub echo: bool,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        Self {
+            baud: 19200,
+            parity: Parity::None,
+            stop_bits: 1,
+            transport: Transport::Rtu,
+            echo: false,
+        }
+    }
+}
+
+impl SerialSettings {
+    pub const BAUD_RATES: [u32; 8] = [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
+
+    pub fn is_valid(&self) -> bool {
+        Self::BAUD_RATES.contains(&self.baud) && (self.stop_bits == 1 || self.stop_bits == 2)
+    }
+
+    pub fn data_bits(&self) -> u32 {
+        if self.transport == Transport::Ascii && self.parity != Parity::None { 7 } else { 8 }
+    }
+
+    /// Bits on the line per character: start, data, optional parity and stop bits
+    pub fn bits_per_char(&self) -> u32 {
+        let parity = if self.parity == Parity::None { 0 } else { 1 };
+        1 + self.data_bits() + parity + self.stop_bits as u32
+    }
+}
+
+/// Configuration persisted across power cycles
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub units: [UnitSettings; 4],
+    /// unit id of the site aggregate, 0 when disabled
+    pub site_id: u8,
+    pub serial: SerialSettings,
+    pub service_pin: ServicePin,
+}
+
+impl Settings {
+    /// Decode a stored payload, fields that were never written keep their defaults
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut settings = Self::default();
+        if data.first() != Some(&SETTINGS_VERSION) {
+            return settings;
+        }
+
+        for (i, unit) in settings.units.iter_mut().enumerate() {
+            if let Some(&value) = data.get(POLICY_OFFSET + i) {
+                unit.start_policy = StartPolicy::from(value);
+            }
+
+            let offset = DELAY_OFFSET + i * 4;
+            if let Some(bytes) = data.get(offset..offset + 4) {
+                let delay = ResponseDelay {
+                    min_ms: u16::from_le_bytes([bytes[0], bytes[1]]),
+                    max_ms: u16::from_le_bytes([bytes[2], bytes[3]]),
+                };
+                if delay.is_valid() {
+                    unit.response_delay = delay;
+                }
+            }
+        }
+
+        match data.get(SITE_OFFSET) {
+            Some(&value) if value != 0xff => { settings.site_id = value; },
+            _ => {}
+        }
+
+        if let Some(bytes) = data.get(BAUD_OFFSET..STOP_BITS_OFFSET + 1) {
+            let serial = SerialSettings {
+                baud: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                parity: Parity::from(bytes[PARITY_OFFSET - BAUD_OFFSET]),
+                stop_bits: bytes[STOP_BITS_OFFSET - BAUD_OFFSET],
+                transport: Transport::from(data.get(TRANSPORT_OFFSET).copied().unwrap_or(0)),
+                echo: data.get(ECHO_OFFSET) == Some(&0x01),
+            };
+            if serial.is_valid() {
+                settings.serial = serial;
+            }
+        }
+
+        // erased bytes pad a short pin
+        if let Some(bytes) = data.get(PIN_OFFSET..PIN_OFFSET + ServicePin::MAX_LEN) {
+            let len = bytes.iter().position(|b| *b == 0xff).unwrap_or(bytes.len());
+            if let Some(pin) = core::str::from_utf8(&bytes[..len]).ok().and_then(ServicePin::parse) {
+                settings.service_pin = pin;
+            }
+        }
+
+        settings
+    }
+
+    /// Encode into `buffer`, returns the number of bytes used
+    pub fn to_bytes(&self, buffer: &mut [u8]) -> usize {
+        buffer[VERSION_OFFSET] = SETTINGS_VERSION;
+        for (i, unit) in self.units.iter().enumerate() {
+            buffer[POLICY_OFFSET + i] = unit.start_policy.to();
+
+            let offset = DELAY_OFFSET + i * 4;
+            buffer[offset..offset + 2].copy_from_slice(&unit.response_delay.min_ms.to_le_bytes());
+            buffer[offset + 2..offset + 4].copy_from_slice(&unit.response_delay.max_ms.to_le_bytes());
+        }
+        buffer[SITE_OFFSET] = self.site_id;
+        buffer[BAUD_OFFSET..BAUD_OFFSET + 4].copy_from_slice(&self.serial.baud.to_le_bytes());
+        buffer[PARITY_OFFSET] = self.serial.parity.to();
+        buffer[STOP_BITS_OFFSET] = self.serial.stop_bits;
+
+        buffer[TRANSPORT_OFFSET] = self.serial.transport.to();
+        buffer[ECHO_OFFSET] = self.serial.echo as u8;
+        buffer[PIN_OFFSET..PIN_OFFSET + ServicePin::MAX_LEN].copy_from_slice(&self.service_pin.digits);
+
+        PIN_OFFSET + ServicePin::MAX_LEN
+    }
+
+    /// Apply the per unit settings to the bank of chargers
+    pub fn apply(&self, chargers: &mut [EVCharger; 4]) {
+        for (chrg, unit) in chargers.iter_mut().zip(self.units.iter()) {
+            chrg.set_start_policy(unit.start_policy);
+            chrg.set_response_delay(unit.response_delay);
+        }
+    }
+}
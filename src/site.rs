@@ -0,0 +1,105 @@
+use heapless::Vec;
+use rtt_target::rprintln;
+
+use crate::ev_charger::*;
+use crate::modbus::*;
+
+// number of units in each charger state, indexed by the state code 0x0 - 0xf
+const STATE_COUNT_BASE: u16 = 0x3100;
+const TOTAL_CURRENT: u16 = 0x3110;
+const TOTAL_POWER_HI: u16 = 0x3112;
+const TOTAL_POWER_LO: u16 = 0x3113;
+const SESSION_ENERGY_HI: u16 = 0x3114;
+const SESSION_ENERGY_LO: u16 = 0x3115;
+const GLOBAL_ENABLE: u16 = 0x4100;
+
+/// Virtual station controller unit summarising the whole bank of chargers
+pub struct SiteUnit {
+    unit_id: u8,
+    global_enable: u16,
+}
+
+impl SiteUnit {
+    /// * `unit_id` - modbus unit id served by the site aggregate, 0 disables it.
+    pub fn new(unit_id: u8) -> Self {
+        Self {
+            unit_id,
+            global_enable: 0,
+        }
+    }
+
+    pub fn get_id(&self) -> u8 {
+        self.unit_id
+    }
+
+    pub fn set_id(&mut self, unit_id: u8) {
+        self.unit_id = unit_id;
+    }
+
+    pub fn query(&mut self, request: &ModbusFrame, chargers: &mut [EVCharger; 4]) -> Result<ModbusFrame, Refusal> {
+
+        if self.unit_id == 0 || request.unit_id != self.unit_id {return Err(Refusal::NotForThisUnit)};
+
+        let addr = match request.refers {
+            Reference::Address(addr) => {addr},
+            _ => {return Err(Refusal::IllegalAddress)}
+        };
+
+        match request.command {
+            3 if addr == GLOBAL_ENABLE && request.value == 1 => {
+                Ok(request.read_reply_regs(&[self.global_enable]))
+            },
+            4 => { self.read_block(request, addr, chargers) },
+            6 if addr == GLOBAL_ENABLE => {
+                self.global_enable = request.value;
+                Self::fan_out(chargers, request.value);
+                Ok(request.write_reply(self.global_enable))
+            },
+            3 | 6 => { Err(Refusal::IllegalAddress) },
+            _ => { Err(Refusal::IllegalFunction) }
+        }
+    }
+
+    fn read_block(&self, request: &ModbusFrame, addr: u16, chargers: &[EVCharger; 4]) -> Result<ModbusFrame, Refusal> {
+        let count = request.value as usize;
+        if count == 0 || count > MAX_REGISTERS {
+            return Err(Refusal::IllegalValue);
+        }
+
+        let mut values: Vec<u16, MAX_REGISTERS> = Vec::new();
+        for i in 0..count {
+            let _ = values.push(Self::read_input(addr.wrapping_add(i as u16), chargers)?);
+        }
+
+        Ok(request.read_reply_regs(&values))
+    }
+
+    fn read_input(addr: u16, chargers: &[EVCharger; 4]) -> Result<u16, Refusal> {
+        let total_power: u32 = chargers.iter().map(|chrg| chrg.power()).sum();
+        let session_energy: u32 = chargers.iter().map(|chrg| chrg.session_energy()).sum();
+
+        match addr {
+            addr if (STATE_COUNT_BASE..STATE_COUNT_BASE + 0x10).contains(&addr) => {
+                let code = addr - STATE_COUNT_BASE;
+                Ok(chargers.iter().filter(|chrg| chrg.current_state() & 0x000f == code).count() as u16)
+            },
+            TOTAL_CURRENT => { Ok(chargers.iter().map(|chrg| chrg.total_current()).sum()) },
+            TOTAL_POWER_HI => { Ok((total_power >> 16) as u16) },
+            TOTAL_POWER_LO => { Ok(total_power as u16) },
+            SESSION_ENERGY_HI => { Ok((session_energy >> 16) as u16) },
+            SESSION_ENERGY_LO => { Ok(session_energy as u16) },
+            _ => { Err(Refusal::IllegalAddress) }
+        }
+    }
+
+    /// Pass a global enable on to every unit as a CHARGE_CONTROL write
+    fn fan_out(chargers: &mut [EVCharger; 4], value: u16) {
+        for chrg in chargers {
+            let unit_id = chrg.get_id();
+            let request = ModbusFrame::new(unit_id, 6, Reference::Address(CHARGE_CONTROL), value);
+            if let Err(err) = chrg.query(&request) {
+                rprintln!("site: unit {} {:?}", unit_id, err);
+            }
+        }
+    }
+}
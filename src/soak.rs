@@ -0,0 +1,181 @@
+use crate::hal::pac::TIM2;
+use crate::hal::timer::Counter;
+use crate::hal::prelude::*;
+use fugit::Instant;
+
+use rtt_target::rprintln;
+
+use crate::display::KeyEvent;
+use crate::ev_charger::*;
+use crate::modbus::*;
+use crate::prng::Prng;
+
+const SECOND: u32 = 1000;
+
+/// Drives the bank of chargers through random but reproducible sessions
+///
+/// every action goes through the same paths as the keys and modbus writes
+/// so the backend sees the same behaviour as on the bench
+pub struct SoakDriver<'a> {
+    sys_timer: &'a Counter<TIM2, 1000>,
+    rng: Prng,
+    seed: u32,
+    enabled: bool,
+    next_action: [Instant<u32, 1, 1000>; 4],
+}
+
+impl <'a>SoakDriver<'a> {
+    pub fn new(sys_timer: &'a Counter<TIM2, 1000>) -> Self {
+        Self {
+            sys_timer,
+            rng: Prng::new(0),
+            seed: 0,
+            enabled: false,
+            next_action: [sys_timer.now(); 4],
+        }
+    }
+
+    /// Start soaking with the given seed
+    pub fn start(&mut self, seed: u32) {
+        self.seed = seed;
+        self.rng = Prng::new(seed);
+        self.enabled = true;
+
+        // stagger the units so they do not all act on the same tick
+        let now = self.sys_timer.now();
+        for next in &mut self.next_action {
+            *next = now + (self.rng.range(1, 10) * SECOND).millis();
+        }
+        rprintln!("soak started, seed: {}", seed);
+    }
+
+    /// A seed derived from the system clock, for runs started without one
+    pub fn clock_seed(&self) -> u32 {
+        self.sys_timer.now().ticks()
+    }
+
+    pub fn stop(&mut self) {
+        self.enabled = false;
+        rprintln!("soak stopped");
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    pub fn run(&mut self, chargers: &mut [EVCharger; 4]) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = self.sys_timer.now();
+        for (i, chrg) in chargers.iter_mut().enumerate() {
+            if now < self.next_action[i] {
+                continue;
+            }
+
+            let delay = self.act(chrg);
+            self.next_action[i] = now + delay.millis();
+        }
+    }
+
+    /// Perform the next step of a unit's life and return msec until the following one
+    fn act(&mut self, chrg: &mut EVCharger) -> u32 {
+        match chrg.charger_state() {
+            ChgState::Wait if chrg.start_policy() == StartPolicy::MasterEnable => {
+                // master enables the unit
+                Self::write(chrg, CHARGE_CONTROL, 1);
+                self.rng.range(5, 60) * SECOND
+            },
+            ChgState::Wait => {
+                // vehicle plugs in
+                Self::press_key(chrg);
+                self.rng.range(2, 10) * SECOND
+            },
+            ChgState::Standby => {
+                // vehicle plugs in
+                Self::press_key(chrg);
+                self.rng.range(2, 10) * SECOND
+            },
+            ChgState::Connect => {
+                // vehicle is authorised and charges for the session length
+                match chrg.start_policy() {
+                    StartPolicy::MasterEnable => { Self::write(chrg, CHARGE_CONTROL, 1); },
+                    StartPolicy::Rfid => { Self::swipe_card(chrg); },
+                    StartPolicy::AutoStart => {},
+                }
+                self.rng.range(60, 1800) * SECOND
+            },
+            ChgState::Charge => {
+                if self.rng.chance(2) {
+                    // residual current trip, needs a service reset
+                    rprintln!("soak: unit {} rcd trip", chrg.get_id());
+                    chrg.trip_rcd();
+                    self.rng.range(60, 600) * SECOND
+                } else if self.rng.chance(5) {
+                    // supply fault
+                    Self::write(chrg, SERVICE_CONTROL, 1);
+                    self.rng.range(10, 60) * SECOND
+                } else if self.rng.chance(10) {
+                    // vehicle stops drawing for a while
+                    Self::pause_vehicle(chrg, true);
+                    self.rng.range(30, 300) * SECOND
+                } else if self.rng.chance(30) {
+                    // master ends the session
+                    Self::write(chrg, CHARGE_CONTROL, 0);
+                    self.rng.range(30, 600) * SECOND
+                } else {
+                    // vehicle unplugs
+                    Self::press_key(chrg);
+                    self.rng.range(30, 600) * SECOND
+                }
+            },
+            ChgState::SuspendedEV => {
+                Self::pause_vehicle(chrg, false);
+                self.rng.range(60, 600) * SECOND
+            },
+            ChgState::Abnormal if chrg.rcd_latched() => {
+                // service engineer resets the trip
+                Self::write(chrg, SERVICE_CONTROL, RCD_RESET_ARM);
+                Self::write(chrg, SERVICE_CONTROL, RCD_RESET_CONFIRM);
+                self.rng.range(5, 30) * SECOND
+            },
+            ChgState::Outage => {
+                Self::write(chrg, SERVICE_CONTROL, 0);
+                self.rng.range(5, 30) * SECOND
+            },
+            _ => { 10 * SECOND }
+        }
+    }
+
+    fn write(chrg: &mut EVCharger, addr: u16, value: u16) {
+        let request = ModbusFrame::new(chrg.get_id(), 6, Reference::Address(addr), value);
+        rprintln!("soak: unit {} write 0x{:04x} = {}", chrg.get_id(), addr, value);
+        if let Err(err) = chrg.query(&request) {
+            rprintln!("soak: {:?}", err);
+        }
+    }
+
+    fn pause_vehicle(chrg: &mut EVCharger, paused: bool) {
+        rprintln!("soak: unit {} vehicle paused {}", chrg.get_id(), paused);
+        chrg.set_vehicle_paused(paused);
+    }
+
+    fn swipe_card(chrg: &mut EVCharger) {
+        let key = chrg.aux_key();
+        rprintln!("soak: unit {} rfid key {}", chrg.get_id(), key);
+        chrg.on_key_event(&KeyEvent::KeyDown { key });
+        chrg.on_key_event(&KeyEvent::KeyUp { key });
+    }
+
+    fn press_key(chrg: &mut EVCharger) {
+        let key = chrg.service_key();
+        rprintln!("soak: unit {} key {}", chrg.get_id(), key);
+        chrg.on_key_event(&KeyEvent::KeyDown { key });
+        chrg.on_key_event(&KeyEvent::KeyUp { key });
+    }
+}
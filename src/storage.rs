@@ -0,0 +1,205 @@
+use crate::hal::flash::{Error, FlashExt, LockedFlash};
+use crate::hal::pac::FLASH;
+
+use rtt_target::rprintln;
+
+use crate::ev_charger::EVCharger;
+use crate::modbus::ModbusFrame;
+use crate::settings::*;
+
+// The top of the STM32F405 flash is kept out of the program area (see memory.x)
+const SECTOR_SIZE: usize = 0x20000;
+const SETTINGS_SECTORS: [u8; 2] = [10, 11];
+const ENERGY_SECTORS: [u8; 2] = [8, 9];
+
+// magic, sequence number and crc around the payload of each record
+const RECORD_MAGIC: [u8; 2] = [0x4a, 0x43];
+const RECORD_OVERHEAD: usize = 6;
+const MAX_RECORD_LEN: usize = 64;
+const ENERGY_RECORD_LEN: usize = 16 + RECORD_OVERHEAD;
+
+/// Append only log of fixed size records over two flash sectors used in turn
+///
+/// every save programs the next free record, once a sector is full the other one is
+/// erased and takes the next record. The full sector still holds the newest value while
+/// the other is erased, so a power loss then loses nothing. The newest record with a
+/// valid crc is the current value, records carry a sequence number to tell which
+/// sector holds it.
+pub struct FlashLog {
+    sectors: [u8; 2],
+    active: usize,
+    record_len: usize,
+    next: usize,
+    sequence: u16,
+}
+
+impl FlashLog {
+    pub fn new(flash: &LockedFlash, sectors: [u8; 2], record_len: usize) -> Self {
+        let mut log = Self {
+            sectors,
+            active: 0,
+            record_len,
+            next: 0,
+            sequence: 0,
+        };
+
+        // continue in the sector holding the newest record
+        let newest = [log.newest(flash, 0), log.newest(flash, 1)];
+        log.active = match newest {
+            [Some((first, _)), Some((second, _))] if (second.wrapping_sub(first) as i16) > 0 => { 1 },
+            [None, Some(_)] => { 1 },
+            _ => { 0 },
+        };
+        log.sequence = newest[log.active].map(|(sequence, _)| sequence).unwrap_or(0);
+
+        // find the first erased record
+        let data = log.sector_data(flash, log.active);
+        while log.next + record_len <= SECTOR_SIZE &&
+              data[log.next..log.next + record_len].iter().any(|b| *b != 0xff) {
+            log.next += record_len;
+        }
+
+        log
+    }
+
+    /// Payload of the newest valid record
+    pub fn latest<'f>(&self, flash: &'f LockedFlash) -> Option<&'f [u8]> {
+        self.newest(flash, self.active).map(|(_, payload)| payload)
+    }
+
+    /// Sequence number and payload of the newest valid record in one of the sectors
+    fn newest<'f>(&self, flash: &'f LockedFlash, index: usize) -> Option<(u16, &'f [u8])> {
+        let data = self.sector_data(flash, index);
+        data.chunks_exact(self.record_len).rev().find_map(Self::payload)
+    }
+
+    fn sector_data<'f>(&self, flash: &'f LockedFlash, index: usize) -> &'f [u8] {
+        let offset = Self::sector_offset(self.sectors[index]);
+        &flash.read()[offset..offset + SECTOR_SIZE]
+    }
+
+    /// Offset of one of the 128K sectors (5 - 11) from the start of the flash
+    fn sector_offset(sector: u8) -> usize {
+        (sector as usize - 4) * SECTOR_SIZE
+    }
+
+    fn payload(record: &[u8]) -> Option<(u16, &[u8])> {
+        if record[0..2] != RECORD_MAGIC || ModbusFrame::calculate_crc16(record) != 0 {
+            return None;
+        }
+        let sequence = u16::from_le_bytes([record[2], record[3]]);
+        Some((sequence, &record[4..record.len() - 2]))
+    }
+
+    /// Program a new record, moving to the other sector when this one is full
+    pub fn append(&mut self, flash: &mut LockedFlash, payload: &[u8]) -> Result<(), Error> {
+        let sequence = self.sequence.wrapping_add(1);
+
+        let mut record = [0xffu8; MAX_RECORD_LEN];
+        let record = &mut record[..self.record_len];
+
+        let len = payload.len().min(self.record_len - RECORD_OVERHEAD);
+        record[0..2].copy_from_slice(&RECORD_MAGIC);
+        record[2..4].copy_from_slice(&sequence.to_le_bytes());
+        record[4..4 + len].copy_from_slice(&payload[..len]);
+        let crc = ModbusFrame::calculate_crc16(&record[..self.record_len - 2]);
+        record[self.record_len - 2..].copy_from_slice(&crc.to_le_bytes());
+
+        let mut unlocked = flash.unlocked();
+        if self.next + self.record_len > SECTOR_SIZE {
+            let other = 1 - self.active;
+            rprintln!("erasing flash sector {}", self.sectors[other]);
+            unlocked.erase(self.sectors[other])?;
+            self.active = other;
+            self.next = 0;
+        }
+
+        let offset = Self::sector_offset(self.sectors[self.active]);
+        unlocked.program(offset + self.next, record.iter())?;
+        self.next += self.record_len;
+        self.sequence = sequence;
+        Ok(())
+    }
+}
+
+/// Persistent storage of the juice box configuration
+pub struct Storage {
+    flash: LockedFlash,
+    settings_log: FlashLog,
+    settings: Settings,
+    energy_log: FlashLog,
+    energy: [u32; 4],
+}
+
+impl Storage {
+    pub fn new(flash: FLASH) -> Self {
+        let flash = LockedFlash::new(flash);
+        let settings_log = FlashLog::new(&flash, SETTINGS_SECTORS, SETTINGS_RECORD_LEN);
+        let settings = match settings_log.latest(&flash) {
+            Some(payload) => { Settings::from_bytes(payload) },
+            None => { Settings::default() },
+        };
+
+        rprintln!("settings: {:?}", settings);
+
+        let energy_log = FlashLog::new(&flash, ENERGY_SECTORS, ENERGY_RECORD_LEN);
+        let mut energy = [0u32; 4];
+        if let Some(payload) = energy_log.latest(&flash) {
+            for (i, wh) in energy.iter_mut().enumerate() {
+                *wh = u32::from_le_bytes(payload[i * 4..i * 4 + 4].try_into().unwrap());
+            }
+        }
+
+        rprintln!("lifetime energy: {:?}", energy);
+
+        Self {
+            flash,
+            settings_log,
+            settings,
+            energy_log,
+            energy,
+        }
+    }
+
+    /// Lifetime energy meters (Wh) as last saved, indexed by ui bank
+    pub fn energy(&self) -> [u32; 4] {
+        self.energy
+    }
+
+    /// Save the lifetime energy meters of the bank, skipped when nothing changed
+    /// since the last save to spare the flash
+    pub fn save_energy(&mut self, chargers: &[EVCharger; 4]) -> Result<(), Error> {
+        let mut energy = [0u32; 4];
+        for (wh, chrg) in energy.iter_mut().zip(chargers.iter()) {
+            *wh = chrg.lifetime_energy();
+        }
+
+        if energy == self.energy {
+            return Ok(());
+        }
+
+        let mut payload = [0u8; 16];
+        for (i, wh) in energy.iter().enumerate() {
+            payload[i * 4..i * 4 + 4].copy_from_slice(&wh.to_le_bytes());
+        }
+
+        self.energy_log.append(&mut self.flash, &payload)?;
+        self.energy = energy;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
+    /// Write the current settings to flash
+    pub fn save(&mut self) -> Result<(), Error> {
+        let mut payload = [0xffu8; SETTINGS_RECORD_LEN - RECORD_OVERHEAD];
+        let len = self.settings.to_bytes(&mut payload);
+        self.settings_log.append(&mut self.flash, &payload[..len])
+    }
+}
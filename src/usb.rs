@@ -10,14 +10,65 @@ use usbd_serial::SerialPort;
 use rtt_target::rprintln;
 
 const COM_MAX_LEN: usize = 128;
+const REPLY_MAX_LEN: usize = 2048;
+
+type Reply = String<REPLY_MAX_LEN>;
 
 use crate::ev_charger::*;
+use crate::soak::SoakDriver;
+use crate::storage::Storage;
+use crate::site::SiteUnit;
+use crate::clock::Clock;
+use crate::settings::{Parity, ResponseDelay, SerialSettings, ServicePin, Transport};
+use crate::serial::ModbusTransceiver;
+use crate::fault::*;
+use crate::mirror::Mirror;
+use crate::modbus::*;
+use crate::answer_local;
+use fugit::{ExtU32, Instant};
+
+// silence on the usb side that ends a frame passed through the gateway
+const GATEWAY_GAP: u32 = 5;
+
+/// What the usb serial port is carrying
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum PortMode {
+    /// text commands and replies
+    Console,
+    /// raw modbus rtu frames passed to and from the rs-485 bus
+    Gateway,
+    /// modbus tcp frames for the local units, bridged to a socket by the host
+    Mbap,
+}
+
+/// Counters of the frames passed through the gateway
+#[derive(Clone, Copy, Default, Debug)]
+struct GatewayStats {
+    forwarded: u32,
+    local: u32,
+    replies: u32,
+    rejected: u32,
+}
+
+/// Counters of the modbus tcp frames served
+#[derive(Clone, Copy, Default, Debug)]
+struct MbapStats {
+    requests: u32,
+    replies: u32,
+    exceptions: u32,
+    rejected: u32,
+}
 
 pub struct UsbCommandProcessor<'a> {
     device: UsbDevice<'a, UsbBus<USB>>,
     serial: SerialPort<'a, UsbBus<USB>>,
     com_buf: [u8; COM_MAX_LEN],
     com_indx: usize,
+    mode: PortMode,
+    frame_buf: Vec<u8, MAX_MBAP_LEN>,
+    frame_last: Option<Instant<u32, 1, 1000>>,
+    gateway_stats: GatewayStats,
+    mbap_stats: MbapStats,
 }
 
 
@@ -31,6 +82,11 @@ impl <'a>UsbCommandProcessor<'a> {
       Self {
             com_buf: [0u8; COM_MAX_LEN],
             com_indx: 0,
+            mode: PortMode::Console,
+            frame_buf: Vec::new(),
+            frame_last: None,
+            gateway_stats: GatewayStats::default(),
+            mbap_stats: MbapStats::default(),
             serial,
             device
         }
@@ -45,27 +101,47 @@ impl <'a>UsbCommandProcessor<'a> {
                 Ok(len) if len > 0 => {
                     write_offset += len;
                 }
-                _ => {}
+                _ => {
+                    // keep the device serviced so long replies can drain
+                    self.device.poll(&mut [&mut self.serial]);
+                }
             }
         }
 
     }
 
-    pub fn poll(&mut self, chargers: &mut [EVCharger; 4]) {
+    #[allow(clippy::too_many_arguments)]
+    pub fn poll(&mut self,
+                chargers: &mut [EVCharger; 4],
+                soak: &mut SoakDriver,
+                storage: &mut Storage,
+                site: &mut SiteUnit,
+                clock: &Clock,
+                modbus: &mut ModbusTransceiver,
+                mirror: &mut Mirror) {
 
         let mut buf = [0u8; COM_MAX_LEN];
 
         if self.device.poll(&mut [&mut self.serial]) {
             match self.serial.read(&mut buf) {
+                Ok(count) if count > 0 && self.mode != PortMode::Console => {
+                    if self.frame_buf.extend_from_slice(&buf[..count]).is_err() {
+                        self.frame_buf.clear();
+                        self.gateway_stats.rejected += 1;
+                    }
+                    self.frame_last = Some(clock.now());
+                },
                 Ok(count) if count > 0 => {
 
                     let mut count = count;
                     for chr in &buf[0..count] {
-                        self.com_buf[self.com_indx] = *chr;
-                        self.com_indx += 1;
+                        if self.com_indx < COM_MAX_LEN {
+                            self.com_buf[self.com_indx] = *chr;
+                            self.com_indx += 1;
+                        }
                     }
 
-                    if buf[count-1] == '\r' as u8 {
+                    if buf[count-1] == '\r' as u8 && count < COM_MAX_LEN {
                         buf[count] = '\n' as u8;
                         count += 1;
                     }
@@ -74,16 +150,16 @@ impl <'a>UsbCommandProcessor<'a> {
                     let command = core::str::from_utf8(&self.com_buf[0..self.com_indx]).unwrap_or_default();
                     if command.contains("\r"){
                         let end = command.chars().position(|c| c == '\r').unwrap_or_default();
-                        let command = &command[..end];
+                        let command: String<COM_MAX_LEN> = String::from_str(&command[..end]).unwrap_or_default();
+                        self.com_indx = 0;
 
-                        match Self::process_command(command, chargers){
+                        match self.process_command(&command, chargers, soak, storage, site, clock, modbus, mirror){
                             Some(reply) => {
                                 self.write(reply.as_bytes());
 
                             },
                             _ => {}
                         }
-                        self.com_indx = 0;
                     }
 
                 }
@@ -91,9 +167,140 @@ impl <'a>UsbCommandProcessor<'a> {
             }
         }
 
+        // closing the port drops back to the console
+        if self.mode != PortMode::Console && !self.serial.dtr() {
+            rprintln!("{:?}: host closed the port", self.mode);
+            self.mode = PortMode::Console;
+            self.frame_buf.clear();
+            self.frame_last = None;
+        }
+
+        match self.mode {
+            PortMode::Gateway => { self.run_gateway(chargers, site, clock, modbus); },
+            PortMode::Mbap => { self.run_mbap(chargers, site); },
+            PortMode::Console => {},
+        }
+
+        // stream whatever the bus monitor has seen since the last poll, discarding it
+        // while no terminal is attached so the main loop never stalls on the host
+        if let Some(text) = modbus.monitor_mut().take_output() {
+            if self.serial.dtr() && self.mode == PortMode::Console {
+                self.write(text.as_bytes());
+            }
+        }
+
+    }
+
+    /// Pass complete frames from the host onto the bus, or to the local unit owning the
+    /// address, and return the replies
+    fn run_gateway(&mut self,
+                   chargers: &mut [EVCharger; 4],
+                   site: &mut SiteUnit,
+                   clock: &Clock,
+                   modbus: &mut ModbusTransceiver) {
+
+        while let Some(reply) = modbus.take_gateway_reply() {
+            self.gateway_stats.replies += 1;
+            self.write(&reply);
+        }
+
+        match self.frame_last {
+            Some(last) if clock.now() >= last + GATEWAY_GAP.millis() => {},
+            _ => { return },
+        }
+        self.frame_last = None;
+
+        let frame = core::mem::take(&mut self.frame_buf);
+        if frame.len() < 4 || ModbusFrame::calculate_crc16(&frame) != 0 {
+            rprintln!("gateway: bad frame {:?}", &frame[..]);
+            self.gateway_stats.rejected += 1;
+            return;
+        }
+
+        let unit_id = frame[0];
+        let local = unit_id != 0 && (find_index(chargers, unit_id).is_some() || site.get_id() == unit_id);
+        if !local {
+            match modbus.forward(&frame) {
+                Ok(()) => { self.gateway_stats.forwarded += 1; },
+                Err(err) => {
+                    rprintln!("gateway: {}", err);
+                    self.gateway_stats.rejected += 1;
+                },
+            }
+            return;
+        }
+
+        self.gateway_stats.local += 1;
+        let reply = ModbusFrame::decode(&frame).ok().and_then(|request| answer_local(&request, site, chargers).ok());
+        if let Some(reply) = reply {
+            let mut tx_data: [u8; MAX_ADU_LEN] = [0; MAX_ADU_LEN];
+            let len = reply.encode(&mut tx_data);
+            self.gateway_stats.replies += 1;
+            self.write(&tx_data[..len]);
+        }
+    }
+
+    /// Serve modbus tcp frames to the local units, the length in the header splits the stream
+    fn run_mbap(&mut self, chargers: &mut [EVCharger; 4], site: &mut SiteUnit) {
+        while let Some(header) = MbapHeader::decode(&self.frame_buf) {
+            let len = header.frame_len();
+            if header.protocol_id != 0 || header.length < 2 || len > MAX_MBAP_LEN {
+                // lost track of the frame boundaries, start over with the next write
+                rprintln!("mbap: bad header {:?}", header);
+                self.mbap_stats.rejected += 1;
+                self.frame_buf.clear();
+                return;
+            }
+            if self.frame_buf.len() < len {
+                return;
+            }
+
+            let frame: Vec<u8, MAX_MBAP_LEN> = Vec::from_slice(&self.frame_buf[..len]).unwrap();
+            self.frame_buf = Vec::from_slice(&self.frame_buf[len..]).unwrap();
+            self.serve_mbap(&frame, chargers, site);
+        }
+    }
+
+    fn serve_mbap(&mut self, frame: &[u8], chargers: &mut [EVCharger; 4], site: &mut SiteUnit) {
+        let (header, request) = match ModbusFrame::decode_mbap(frame) {
+            Ok(decoded) => { decoded },
+            Err(err) => {
+                rprintln!("mbap: {}", err);
+                self.mbap_stats.rejected += 1;
+                return;
+            },
+        };
+        self.mbap_stats.requests += 1;
+
+        let mut tx_data: [u8; MAX_MBAP_LEN] = [0; MAX_MBAP_LEN];
+        let unit_id = header.unit_id;
+        let local = unit_id != 0 && (find_index(chargers, unit_id).is_some() || site.get_id() == unit_id);
+
+        // like a tcp gateway, report units that are not emulated here as not responding,
+        // and unlike on the bus answer refused requests so the client need not time out
+        let result = if local { answer_local(&request, site, chargers) } else { Err(Refusal::NotForThisUnit) };
+        let len = match result {
+            Ok(reply) => { reply.encode_mbap(header.transaction_id, &mut tx_data) },
+            Err(refusal) => {
+                self.mbap_stats.exceptions += 1;
+                ModbusFrame::mbap_exception(&header, request.command, refusal.exception_code(), &mut tx_data)
+            },
+        };
+
+        self.mbap_stats.replies += 1;
+        self.write(&tx_data[..len]);
     }
 
-    fn process_command(command: &str, chargers: &mut [EVCharger; 4]) -> Option<String<COM_MAX_LEN>>{
+    #[allow(clippy::too_many_arguments)]
+    fn process_command(&mut self,
+                       command: &str,
+                       chargers: &mut [EVCharger; 4],
+                       soak: &mut SoakDriver,
+                       storage: &mut Storage,
+                       site: &mut SiteUnit,
+                       clock: &Clock,
+                       modbus: &mut ModbusTransceiver,
+                       mirror: &mut Mirror) -> Option<Reply>{
         rprintln!("command is: {}",  command);
 
         if command == "get_units" {
@@ -110,7 +317,8 @@ impl <'a>UsbCommandProcessor<'a> {
                     .filter_map(|s| s.parse().ok())
                     .collect();
 
-                if is_unique(&ids){
+                // unit ids may not clash with each other or the site aggregate
+                if ids.len() == 4 && is_unique(&ids) && !ids.contains(&site.get_id()){
                     for i in 0..4{
                         chargers[i].set_id(ids[i]);
                     }
@@ -123,13 +331,477 @@ impl <'a>UsbCommandProcessor<'a> {
             return Some(reply);
         }
 
+        if command == "get_bus_stats" {
+            return bus_stats_reply(modbus);
+        }
+
+        if let Some(args) = parse_args(command, "set_echo[") {
+            let enabled = match (args.first(), args.len()) {
+                (Some(&"on"), 1) => { Some(true) },
+                (Some(&"off"), 1) => { Some(false) },
+                _ => { None },
+            };
+
+            if let Some(enabled) = enabled {
+                modbus.set_echo_check(enabled);
+                storage.settings_mut().serial.echo = enabled;
+                if storage.save().is_err() {
+                    return Some(String::from_str("Failed to save settings!\r\n").unwrap());
+                }
+                return bus_stats_reply(modbus);
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_echo[on|off]\r\n").unwrap());
+        }
+
+        if command == "gateway" {
+            return gateway_reply(&self.gateway_stats, false);
+        }
+
+        if command == "gateway[on]" {
+            // the reply is the last text before the port carries binary frames
+            self.mode = PortMode::Gateway;
+            self.frame_buf.clear();
+            self.frame_last = None;
+            return gateway_reply(&self.gateway_stats, true);
+        }
+
+        if command == "mbap" {
+            return mbap_reply(&self.mbap_stats, false);
+        }
+
+        if command == "mbap[on]" {
+            // the reply is the last text before the port carries binary frames
+            self.mode = PortMode::Mbap;
+            self.frame_buf.clear();
+            return mbap_reply(&self.mbap_stats, true);
+        }
+
+        if command == "master" {
+            return master_reply(modbus);
+        }
+
+        if let Some(args) = parse_args(command, "set_master[") {
+            let timeout_ms: Option<u32> = args.first().and_then(|s| s.parse().ok()).filter(|t| (10..=10_000).contains(t));
+            let retries: Option<u8> = args.get(1).and_then(|s| s.parse().ok()).filter(|r| *r <= 5);
+            if let (Some(timeout_ms), Some(retries), 2) = (timeout_ms, retries, args.len()) {
+                modbus.set_master(timeout_ms, retries);
+                return master_reply(modbus);
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_master[timeout_ms 10-10000,retries 0-5]\r\n").unwrap());
+        }
+
+        if command == "mirror" {
+            return mirror_reply(chargers, mirror);
+        }
+
+        if let Some(args) = parse_args(command, "mirror[") {
+            let index = args.first().and_then(|s| s.parse().ok()).and_then(|id| find_index(chargers, id));
+            let remote = match args.get(1) {
+                Some(&"off") => { Some(None) },
+                // the remote must be a unit on the bus, not one emulated here
+                Some(s) => {
+                    s.parse().ok()
+                    .filter(|id: &u8| *id != 0 && find_index(chargers, *id).is_none() && *id != site.get_id())
+                    .map(Some)
+                },
+                None => { None },
+            };
+
+            if let (Some(index), Some(remote), 2) = (index, remote, args.len()) {
+                mirror.set_remote(chargers, index, remote);
+                return mirror_reply(chargers, mirror);
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: mirror[unit,remote unit|off]\r\n").unwrap());
+        }
+
+        if command == "capture" {
+            return capture_reply(modbus);
+        }
+
+        if let Some(args) = parse_args(command, "capture[") {
+            match (args.first(), args.len()) {
+                (Some(&"on"), 1) => { modbus.capture_mut().set_enabled(true); },
+                (Some(&"off"), 1) => { modbus.capture_mut().set_enabled(false); },
+                (Some(&"clear"), 1) => { modbus.capture_mut().clear(); },
+                (Some(&"dump"), 1) => {
+                    // a text line giving the size, then the pcap file as raw binary
+                    let mut reply: Reply = String::new();
+                    let _ = write!(reply, "pcap[{}]\r\n", modbus.capture().pcap_len());
+                    self.write(reply.as_bytes());
+
+                    let offset_us = clock.epoch_ms(0).unwrap_or(0) * 1000;
+                    modbus.capture().write_pcap(offset_us, |data| self.write(data));
+                    return None;
+                },
+                _ => {
+                    return Some(String::from_str("Invalid!\r\nSyntax: capture[on|off|clear|dump]\r\n").unwrap());
+                }
+            }
+
+            return capture_reply(modbus);
+        }
+
+        if command == "monitor" {
+            return monitor_reply(modbus);
+        }
+
+        if let Some(args) = parse_args(command, "monitor[") {
+            // filters are a unit id and a function code, * matches any
+            let filter = |arg: Option<&&str>| -> Result<Option<u8>, ()> {
+                match arg {
+                    None | Some(&"*") => { Ok(None) },
+                    Some(s) => { s.parse().map(Some).map_err(|_| ()) },
+                }
+            };
+
+            match (args.first(), filter(args.get(1)), filter(args.get(2)), args.len()) {
+                (Some(&"on"), Ok(unit_filter), Ok(function_filter), 1..=3) => {
+                    modbus.monitor_mut().start(unit_filter, function_filter);
+                    return monitor_reply(modbus);
+                },
+                (Some(&"off"), _, _, 1) => {
+                    modbus.monitor_mut().stop();
+                    return monitor_reply(modbus);
+                },
+                _ => {}
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: monitor[on], monitor[on,unit|*,fc|*] or monitor[off]\r\n").unwrap());
+        }
+
+        if command == "get_faults" {
+            return faults_reply(modbus.faults());
+        }
+
+        if command == "clear_faults" {
+            modbus.faults_mut().clear();
+            return faults_reply(modbus.faults());
+        }
+
+        if let Some(args) = parse_args(command, "set_fault[") {
+            let unit_id: Option<u8> = args.first().and_then(|s| s.parse().ok());
+            let fault = args.get(1).and_then(|s| Fault::parse(s));
+            let percent: Option<u8> = args.get(2).and_then(|s| s.parse().ok()).filter(|p| *p <= 100);
+
+            return match (args.len(), unit_id, fault, percent) {
+                (3, Some(unit_id), Some(fault), Some(percent)) => {
+                    match modbus.faults_mut().set(unit_id, fault, percent) {
+                        Ok(()) => faults_reply(modbus.faults()),
+                        Err(err) => {
+                            let mut reply: Reply = String::new();
+                            let _ = write!(reply, "Failed: {}\r\n", err);
+                            Some(reply)
+                        },
+                    }
+                },
+                _ => Some(String::from_str("Invalid!\r\nSyntax: set_fault[unit,drop|late|crc|truncate|unit|fc|exception,percent]\r\n").unwrap()),
+            };
+        }
+
+        if command == "get_serial" {
+            return serial_reply(&storage.settings().serial);
+        }
+
+        if let Some(args) = parse_args(command, "set_serial[") {
+            let line = SerialSettings {
+                baud: args.first().and_then(|s| s.parse().ok()).unwrap_or(0),
+                parity: args.get(1).and_then(|s| Parity::parse(s)).unwrap_or(Parity::None),
+                stop_bits: args.get(2).and_then(|s| s.parse().ok()).unwrap_or(0),
+                transport: args.get(3).and_then(|s| Transport::parse(s)).unwrap_or(Transport::Rtu),
+                echo: storage.settings().serial.echo,
+            };
+            let parity_ok = args.get(1).and_then(|s| Parity::parse(s)).is_some();
+            let transport_ok = args.get(3).is_none_or(|s| Transport::parse(s).is_some());
+
+            if (args.len() == 3 || args.len() == 4) && parity_ok && transport_ok && line.is_valid() {
+                storage.settings_mut().serial = line;
+                if storage.save().is_err() {
+                    return Some(String::from_str("Failed to save settings!\r\n").unwrap());
+                }
+
+                modbus.set_line(&line);
+                return serial_reply(&line);
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_serial[baud,N|E|O,1|2] or set_serial[baud,N|E|O,1|2,rtu|ascii]\r\n").unwrap());
+        }
+
+        if command == "get_time" {
+            return time_reply(clock);
+        }
+
+        if let Some(args) = parse_args(command, "set_time[") {
+            if let (Some(epoch), 1) = (args.first().and_then(|s| s.parse::<u32>().ok()), args.len()) {
+                clock.set_epoch(epoch);
+                return time_reply(clock);
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_time[unix seconds]\r\n").unwrap());
+        }
+
+        if command == "get_site" {
+            return site_reply(site);
+        }
+
+        if let Some(args) = parse_args(command, "set_site[") {
+            let id = match args.first() {
+                Some(&"off") => Some(0),
+                Some(s) => s.parse::<u8>().ok().filter(|id| *id != 0),
+                None => None,
+            };
+
+            match id {
+                Some(id) if args.len() == 1 && find_index(chargers, id).is_none() => {
+                    site.set_id(id);
+                    storage.settings_mut().site_id = id;
+                    if storage.save().is_err() {
+                        return Some(String::from_str("Failed to save settings!\r\n").unwrap());
+                    }
+                    return site_reply(site);
+                },
+                _ => {}
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_site[id] set_site[off], id must not be a unit\r\n").unwrap());
+        }
+
+        if command == "soak" {
+            return soak_reply(soak);
+        }
+
+        if let Some(args) = parse_args(command, "soak[") {
+            match (args.first(), args.get(1).map(|s| s.parse::<u32>())) {
+                (Some(&"on"), None) => {
+                    // no seed given so pick one from the clock, it is reported back for reruns
+                    soak.start(soak.clock_seed());
+                    return soak_reply(soak);
+                },
+                (Some(&"on"), Some(Ok(seed))) => {
+                    soak.start(seed);
+                    return soak_reply(soak);
+                },
+                (Some(&"off"), None) => {
+                    soak.stop();
+                    return soak_reply(soak);
+                },
+                _ => {}
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: soak[on] soak[on,seed] soak[off]\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "get_phases[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => phases_reply(chrg),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_phases[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "set_phases[") {
+            let values: Vec<u8, 3> = args.iter().filter_map(|s| s.parse().ok()).take(3).collect();
+            if args.len() == 3 && values.len() == 3 {
+                if let Some(chrg) = find_charger(chargers, values[0]) {
+                    if chrg.set_phases(values[1], values[2]).is_ok() {
+                        return phases_reply(chrg);
+                    }
+                }
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_phases[unit,1|3,1-3]\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "get_policy[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => policy_reply(chrg),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_policy[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "set_policy[") {
+            let index = args.first().and_then(|s| s.parse().ok()).and_then(|id| find_index(chargers, id));
+            let policy = args.get(1).and_then(|s| StartPolicy::parse(s));
+            if let (Some(index), Some(policy), 2) = (index, policy, args.len()) {
+                chargers[index].set_start_policy(policy);
+                storage.settings_mut().units[index].start_policy = policy;
+                if storage.save().is_err() {
+                    return Some(String::from_str("Failed to save settings!\r\n").unwrap());
+                }
+                return policy_reply(&chargers[index]);
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_policy[unit,master|auto|rfid]\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "get_delay[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => delay_reply(chrg),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_delay[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "set_delay[") {
+            // a single value gives a fixed delay, two give the jitter range
+            let index = args.first().and_then(|s| s.parse().ok()).and_then(|id| find_index(chargers, id));
+            let min_ms: Option<u16> = args.get(1).and_then(|s| s.parse().ok());
+            let max_ms: Option<u16> = match args.get(2) {
+                Some(s) => { s.parse().ok() },
+                None => { min_ms },
+            };
+
+            if let (Some(index), Some(min_ms), Some(max_ms), 2..=3) = (index, min_ms, max_ms, args.len()) {
+                let delay = ResponseDelay { min_ms, max_ms };
+                if delay.is_valid() {
+                    chargers[index].set_response_delay(delay);
+                    storage.settings_mut().units[index].response_delay = delay;
+                    if storage.save().is_err() {
+                        return Some(String::from_str("Failed to save settings!\r\n").unwrap());
+                    }
+                    return delay_reply(&chargers[index]);
+                }
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_delay[unit,ms] or set_delay[unit,min_ms,max_ms], up to 5000 ms\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "vehicle_pause[") {
+            let chrg = args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id));
+            match (chrg, args.get(1), args.len()) {
+                (Some(chrg), Some(&"on"), 2) => {
+                    chrg.set_vehicle_paused(true);
+                    return vehicle_reply(chrg);
+                },
+                (Some(chrg), Some(&"off"), 2) => {
+                    chrg.set_vehicle_paused(false);
+                    return vehicle_reply(chrg);
+                },
+                _ => {}
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: vehicle_pause[unit,on|off]\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "rcd_trip[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => {
+                    chrg.trip_rcd();
+                    rcd_reply(chrg)
+                },
+                None => Some(String::from_str("Invalid!\r\nSyntax: rcd_trip[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "get_rcd[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => rcd_reply(chrg),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_rcd[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "get_lock[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => lock_reply(chrg),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_lock[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "lock_fault[") {
+            let chrg = args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id));
+            match (chrg, args.get(1), args.len()) {
+                (Some(chrg), Some(&"on"), 2) => {
+                    chrg.set_lock_fault(true);
+                    return lock_reply(chrg);
+                },
+                (Some(chrg), Some(&"off"), 2) => {
+                    chrg.set_lock_fault(false);
+                    return lock_reply(chrg);
+                },
+                _ => {}
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: lock_fault[unit,on|off]\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "get_energy[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => energy_reply(chrg),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_energy[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "set_energy[") {
+            let index = args.first().and_then(|s| s.parse().ok()).and_then(|id| find_index(chargers, id));
+            let wh = args.get(1).and_then(|s| s.parse::<u32>().ok());
+            let pin = storage.settings().service_pin;
+            match (index, wh, args.get(2), args.len()) {
+                (Some(index), Some(wh), Some(entered), 3) if pin.matches(entered) => {
+                    chargers[index].set_lifetime_energy(wh);
+                    if storage.save_energy(chargers).is_err() {
+                        return Some(String::from_str("Failed to save energy!\r\n").unwrap());
+                    }
+                    return energy_reply(&chargers[index]);
+                },
+                (Some(_), Some(_), Some(_), 3) if !pin.is_set() => {
+                    return Some(String::from_str("Access denied!\r\nNo service pin, configure one with set_pin[pin]\r\n").unwrap());
+                },
+                (Some(_), Some(_), Some(_), 3) => {
+                    return Some(String::from_str("Access denied!\r\n").unwrap());
+                },
+                _ => {}
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_energy[unit,wh,pin]\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "set_pin[") {
+            // the first pin may be set freely, changing it needs the current one
+            let current = storage.settings().service_pin;
+            let new = match (args.as_slice(), current.is_set()) {
+                ([new], false) => { ServicePin::parse(new) },
+                ([old, new], true) if current.matches(old) => { ServicePin::parse(new) },
+                ([_, _], true) => {
+                    return Some(String::from_str("Access denied!\r\n").unwrap());
+                },
+                _ => { None },
+            };
+
+            if let Some(new) = new {
+                storage.settings_mut().service_pin = new;
+                if storage.save().is_err() {
+                    return Some(String::from_str("Failed to save settings!\r\n").unwrap());
+                }
+                return Some(String::from_str("service pin set\r\n").unwrap());
+            }
+
+            return Some(String::from_str("Invalid!\r\nSyntax: set_pin[pin] or set_pin[old_pin,new_pin], 4 to 8 digits\r\n").unwrap());
+        }
+
+        if let Some(args) = parse_args(command, "get_events[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => events_reply(chrg, clock),
+                None => Some(String::from_str("Invalid!\r\nSyntax: get_events[unit]\r\n").unwrap()),
+            };
+        }
+
+        if let Some(args) = parse_args(command, "clear_events[") {
+            return match args.first().and_then(|s| s.parse().ok()).and_then(|id| find_charger(chargers, id)) {
+                Some(chrg) => {
+                    chrg.clear_events();
+                    events_reply(chrg, clock)
+                },
+                None => Some(String::from_str("Invalid!\r\nSyntax: clear_events[unit]\r\n").unwrap()),
+            };
+        }
+
         None
     }
 
 }
 
-fn units_reply(chargers: &mut [EVCharger; 4]) -> Option<String<COM_MAX_LEN>>{
-    let mut reply: String<COM_MAX_LEN> = String::new();
+fn units_reply(chargers: &mut [EVCharger; 4]) -> Option<Reply>{
+    let mut reply: Reply = String::new();
     let _ = write!(reply,
         "units[{}, {}, {}, {}]\r\n",
         chargers[0].get_id(),
@@ -151,3 +823,305 @@ fn is_unique(ids: &Vec<u8, 4>) -> bool{
     };
     return true;
 }
+
+fn policy_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "unit {} policy[{}]\r\n",
+        charger.get_id(),
+        charger.start_policy().name(), );
+
+    Some(reply)
+}
+
+fn delay_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let delay = charger.response_delay();
+    let _ = write!(reply,
+        "unit {} delay[{}..{} ms]\r\n",
+        charger.get_id(),
+        delay.min_ms,
+        delay.max_ms, );
+
+    Some(reply)
+}
+
+fn vehicle_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "unit {} vehicle_pause[{}] state[{:?}]\r\n",
+        charger.get_id(),
+        if charger.vehicle_paused() {"on"} else {"off"},
+        charger.charger_state(), );
+
+    Some(reply)
+}
+
+fn rcd_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "unit {} rcd[{}] trips[{}]\r\n",
+        charger.get_id(),
+        if charger.rcd_latched() {"latched"} else {"ok"},
+        charger.rcd_trips(), );
+
+    Some(reply)
+}
+
+fn lock_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "unit {} lock[{:?}] lock_fault[{}]\r\n",
+        charger.get_id(),
+        charger.lock_state(),
+        if charger.lock_fault() {"on"} else {"off"}, );
+
+    Some(reply)
+}
+
+fn energy_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "unit {} energy[{} Wh]\r\n",
+        charger.get_id(),
+        charger.lifetime_energy(), );
+
+    Some(reply)
+}
+
+fn bus_stats_reply(modbus: &ModbusTransceiver) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let stats = modbus.stats();
+    let _ = write!(reply,
+        "bus frames[{}] crc_errors[{}] framing_errors[{}] overruns[{}] back_to_back[{}] replies[{}]\r\n",
+        stats.frames,
+        stats.crc_errors,
+        stats.framing_errors,
+        stats.overruns,
+        stats.back_to_back,
+        stats.replies, );
+    let _ = write!(reply,
+        "echo[{}] echoes[{}] collisions[{}] missing[{}] duplicate_addresses[{}]\r\n",
+        if modbus.echo_check() {"on"} else {"off"},
+        stats.echoes,
+        stats.collisions,
+        stats.missing_echoes,
+        stats.duplicate_addresses, );
+
+    let counts = modbus.faults().counts();
+    let _ = write!(reply, "injected");
+    for fault in Fault::ALL {
+        let _ = write!(reply, " {}[{}]", fault.name(), counts[fault.index()]);
+    }
+    let _ = write!(reply, "\r\n");
+
+    Some(reply)
+}
+
+fn gateway_reply(stats: &GatewayStats, starting: bool) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "gateway[{}] forwarded[{}] local[{}] replies[{}] rejected[{}]\r\n",
+        if starting {"on"} else {"off"},
+        stats.forwarded,
+        stats.local,
+        stats.replies,
+        stats.rejected, );
+    if starting {
+        let _ = write!(reply, "close the port to return to the console\r\n");
+    }
+
+    Some(reply)
+}
+
+fn mbap_reply(stats: &MbapStats, starting: bool) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "mbap[{}] requests[{}] replies[{}] exceptions[{}] rejected[{}]\r\n",
+        if starting {"on"} else {"off"},
+        stats.requests,
+        stats.replies,
+        stats.exceptions,
+        stats.rejected, );
+    if starting {
+        let _ = write!(reply, "close the port to return to the console\r\n");
+    }
+
+    Some(reply)
+}
+
+fn master_reply(modbus: &ModbusTransceiver) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let stats = modbus.stats();
+    let _ = write!(reply,
+        "master timeout[{} ms] retries[{}] requests[{}] retried[{}] timeouts[{}] exceptions[{}]\r\n",
+        modbus.master_timeout_ms(),
+        modbus.master_retries(),
+        stats.master_requests,
+        stats.master_retries,
+        stats.master_timeouts,
+        stats.master_exceptions, );
+
+    Some(reply)
+}
+
+fn mirror_reply(chargers: &[EVCharger; 4], mirror: &Mirror) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    for (i, chrg) in chargers.iter().enumerate() {
+        match mirror.remote(i) {
+            Some(remote) => {
+                let _ = write!(reply,
+                    "unit {} mirror[{}] state[0x{:04x}] failures[{}]\r\n",
+                    chrg.get_id(),
+                    remote,
+                    chrg.mirrored_state(),
+                    mirror.failures(i), );
+            },
+            None => { let _ = write!(reply, "unit {} mirror[off]\r\n", chrg.get_id()); },
+        }
+    }
+
+    Some(reply)
+}
+
+fn capture_reply(modbus: &ModbusTransceiver) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let capture = modbus.capture();
+    let _ = write!(reply,
+        "capture[{}] frames[{}] discarded[{}] pcap[{} bytes]\r\n",
+        if capture.is_enabled() {"on"} else {"off"},
+        capture.records(),
+        capture.discarded(),
+        capture.pcap_len(), );
+
+    Some(reply)
+}
+
+fn monitor_reply(modbus: &ModbusTransceiver) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let monitor = modbus.monitor();
+    if !monitor.is_enabled() {
+        let _ = write!(reply, "monitor[off] dropped[{}]\r\n", monitor.dropped());
+        return Some(reply);
+    }
+
+    let _ = write!(reply, "monitor[on] unit[");
+    match monitor.unit_filter() {
+        Some(id) => { let _ = write!(reply, "{}", id); },
+        None => { let _ = write!(reply, "*"); },
+    }
+    let _ = write!(reply, "] fc[");
+    match monitor.function_filter() {
+        Some(fc) => { let _ = write!(reply, "{}", fc); },
+        None => { let _ = write!(reply, "*"); },
+    }
+    let _ = write!(reply, "] dropped[{}] local units silent\r\n", monitor.dropped());
+
+    Some(reply)
+}
+
+fn faults_reply(faults: &FaultInjector) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let mut any = false;
+    for (unit_id, config) in faults.units() {
+        let _ = write!(reply, "unit {} faults", unit_id);
+        for fault in Fault::ALL {
+            let _ = write!(reply, " {}[{}%]", fault.name(), config.get(fault));
+        }
+        let _ = write!(reply, "\r\n");
+        any = true;
+    }
+
+    if !any {
+        let _ = write!(reply, "faults[off]\r\n");
+    }
+
+    Some(reply)
+}
+
+fn serial_reply(line: &SerialSettings) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "serial[{},{},{},{}]\r\n",
+        line.baud,
+        line.parity.name(),
+        line.stop_bits,
+        line.transport.name(), );
+
+    Some(reply)
+}
+
+fn time_reply(clock: &Clock) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let uptime = clock.uptime_ms();
+    let _ = write!(reply, "uptime[{} s] time[{}]\r\n", uptime / 1000, clock.timestamp(uptime));
+
+    Some(reply)
+}
+
+fn site_reply(site: &SiteUnit) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    match site.get_id() {
+        0 => { let _ = write!(reply, "site[off]\r\n"); },
+        id => { let _ = write!(reply, "site[{}]\r\n", id); },
+    }
+
+    Some(reply)
+}
+
+fn soak_reply(soak: &SoakDriver) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let _ = write!(reply,
+        "soak[{}] seed[{}]\r\n",
+        if soak.is_enabled() {"on"} else {"off"},
+        soak.seed(), );
+
+    Some(reply)
+}
+
+fn phases_reply(charger: &EVCharger) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let (charger_phases, vehicle_phases, active_phases) = charger.get_phases();
+    let _ = write!(reply,
+        "unit {} phases[charger {}, vehicle {}, active {}]\r\n",
+        charger.get_id(),
+        charger_phases,
+        vehicle_phases,
+        active_phases, );
+
+    Some(reply)
+}
+
+fn events_reply(charger: &EVCharger, clock: &Clock) -> Option<Reply>{
+    let mut reply: Reply = String::new();
+    let events = charger.events();
+    let _ = write!(reply,
+        "unit {} events[{}] overflows[{}]\r\n",
+        charger.get_id(),
+        events.len(),
+        events.overflows(), );
+
+    for event in events.iter() {
+        let _ = write!(reply, "{} {}\r\n", clock.timestamp(event.time), event.kind);
+    }
+
+    Some(reply)
+}
+
+/// Split the arguments out of a command of the form `name[arg,arg,...]`
+fn parse_args<'c>(command: &'c str, name: &str) -> Option<Vec<&'c str, 10>>{
+    if !command.starts_with(name) || !command.ends_with("]") {
+        return None;
+    }
+
+    let args = &command[name.len()..command.len() - 1];
+    Some(args.split(",").map(|s| s.trim()).filter(|s| !s.is_empty()).take(10).collect())
+}
+
+fn find_index(chargers: &[EVCharger; 4], unit_id: u8) -> Option<usize>{
+    chargers.iter().position(|chrg| chrg.get_id() == unit_id)
+}
+
+fn find_charger<'b, 'c>(chargers: &'b mut [EVCharger<'c>; 4], unit_id: u8) -> Option<&'b mut EVCharger<'c>>{
+    chargers.iter_mut().find(|chrg| chrg.get_id() == unit_id)
+}